@@ -0,0 +1,163 @@
+//! Accent commands such as \"o, \'{e} and \c{c}.
+//!
+//! Not in detex.l, which drops the accent and keeps the bare letter.
+
+/// Longest accent command `parse_accent` can recognize, for lookahead
+pub const MAX_ACCENT_LEN: usize = 12;
+
+/// Accent command, its Unicode combining mark, and the letters that have a
+/// precomposed form together with those forms (matched by position).
+const ACCENTS: &[(char, char, &str, &str)] = &[
+    // grave
+    ('`', '\u{0300}', "aeinouwyAEINOUWY", "àèìǹòùẁỳÀÈÌǸÒÙẀỲ"),
+    // acute
+    (
+        '\'',
+        '\u{0301}',
+        "acegiklmnoprsuwyzACEGIKLMNOPRSUWYZ",
+        "áćéǵíḱĺḿńóṕŕśúẃýźÁĆÉǴÍḰĹḾŃÓṔŔŚÚẂÝŹ",
+    ),
+    // circumflex
+    (
+        '^',
+        '\u{0302}',
+        "aceghijosuwyzACEGHIJOSUWYZ",
+        "âĉêĝĥîĵôŝûŵŷẑÂĈÊĜĤÎĴÔŜÛŴŶẐ",
+    ),
+    // tilde
+    ('~', '\u{0303}', "aeinouvyAEINOUVY", "ãẽĩñõũṽỹÃẼĨÑÕŨṼỸ"),
+    // macron
+    ('=', '\u{0304}', "aegiouyAEGIOUY", "āēḡīōūȳĀĒḠĪŌŪȲ"),
+    // breve
+    ('u', '\u{0306}', "aegiouAEGIOU", "ăĕğĭŏŭĂĔĞĬŎŬ"),
+    // dot above
+    (
+        '.',
+        '\u{0307}',
+        "abcdefghmnoprstwxyzABCDEFGHIMNOPRSTWXYZ",
+        "ȧḃċḋėḟġḣṁṅȯṗṙṡṫẇẋẏżȦḂĊḊĖḞĠḢİṀṄȮṖṘṠṪẆẊẎŻ",
+    ),
+    // diaeresis
+    (
+        '"',
+        '\u{0308}',
+        "aehiotuwxyAEHIOUWXY",
+        "äëḧïöẗüẅẍÿÄËḦÏÖÜẄẌŸ",
+    ),
+    // ring above
+    ('r', '\u{030a}', "auwyAU", "åůẘẙÅŮ"),
+    // double acute
+    ('H', '\u{030b}', "ouOU", "őűŐŰ"),
+    // caron
+    (
+        'v',
+        '\u{030c}',
+        "acdeghijklnorstuzACDEGHIKLNORSTUZ",
+        "ǎčďěǧȟǐǰǩľňǒřšťǔžǍČĎĚǦȞǏǨĽŇǑŘŠŤǓŽ",
+    ),
+    // dot below
+    (
+        'd',
+        '\u{0323}',
+        "abdehiklmnorstuvwyzABDEHIKLMNORSTUVWYZ",
+        "ạḅḍẹḥịḳḷṃṇọṛṣṭụṿẉỵẓẠḄḌẸḤỊḲḶṂṆỌṚṢṬỤṾẈỴẒ",
+    ),
+    // cedilla
+    (
+        'c',
+        '\u{0327}',
+        "cdeghklnrstCDEGHKLNRST",
+        "çḑȩģḩķļņŗşţÇḐȨĢḨĶĻŅŖŞŢ",
+    ),
+    // ogonek
+    ('k', '\u{0328}', "aeiouAEIOU", "ąęįǫųĄĘĮǪŲ"),
+    // macron below
+    ('b', '\u{0331}', "bdhklnrtzBDKLNRTZ", "ḇḏẖḵḻṉṟṯẕḆḎḴḺṈṞṮẔ"),
+];
+
+/// Accents written as a letter (`\c{c}`, `\v s`) rather than a symbol
+fn is_letter_accent(accent: char) -> bool {
+    accent.is_ascii_alphabetic()
+}
+
+/// Combine an accent with a base letter, preferring a precomposed character
+/// and falling back to the letter followed by a combining mark.
+fn compose(accent: char, base: char) -> Option<String> {
+    let &(_, mark, bases, composed) = ACCENTS.iter().find(|entry| entry.0 == accent)?;
+    match bases.chars().position(|b| b == base) {
+        Some(i) => composed.chars().nth(i).map(String::from),
+        None => Some([base, mark].iter().collect()),
+    }
+}
+
+/// Parse an accent command from the text following its backslash.
+///
+/// Handles the bare form (`"o`), the braced form (`"{o}`), the dotless
+/// `\i` and `\j` as bases, and letter accents followed by a space or
+/// brace (`c{c}`, `v s`). Returns the number of characters the command
+/// spans and the accented text.
+pub fn parse_accent(text: &str) -> Option<(usize, String)> {
+    let chars: Vec<char> = text.chars().collect();
+    let accent = *chars.first()?;
+    if !ACCENTS.iter().any(|entry| entry.0 == accent) {
+        return None;
+    }
+
+    let mut i = 1;
+    let braced = chars.get(i) == Some(&'{');
+    if braced {
+        i += 1;
+        while chars.get(i) == Some(&' ') {
+            i += 1;
+        }
+    } else if is_letter_accent(accent) {
+        // \cite, \url etc. must not be read as accents
+        if chars.get(i) != Some(&' ') {
+            return None;
+        }
+        while chars.get(i) == Some(&' ') {
+            i += 1;
+        }
+    }
+
+    let base = match chars.get(i) {
+        Some('\\') => {
+            let base = *chars.get(i + 1)?;
+            if !(base == 'i' || base == 'j')
+                || chars.get(i + 2).is_some_and(|c| c.is_ascii_alphabetic())
+            {
+                return None;
+            }
+            i += 2;
+            // As in TeX, spaces after the control word \i are skipped
+            if !braced {
+                while chars.get(i) == Some(&' ') {
+                    i += 1;
+                }
+            }
+            base
+        }
+        Some(&c) if c.is_alphabetic() => {
+            i += 1;
+            c
+        }
+        _ => return None,
+    };
+
+    if braced {
+        while chars.get(i) == Some(&' ') {
+            i += 1;
+        }
+        if chars.get(i) != Some(&'}') {
+            return None;
+        }
+        i += 1;
+    }
+
+    Some((i, compose(accent, base)?))
+}
+
+/// Check for a Unicode combining diacritical mark
+pub fn is_combining_mark(c: char) -> bool {
+    ('\u{0300}'..='\u{036f}').contains(&c)
+}
@@ -1,5 +1,9 @@
 //! Configuration constants and options for detex.
 
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
 /// Maximum number of separate paths in TEXINPUTS
 pub const MAX_INPUT_PATHS: usize = 10;
 
@@ -22,14 +26,57 @@ pub const DEFAULT_ENV: &str = "algorithm,align,array,bmatrix,displaymath,eqnarra
     floatfig,floating,longtable,picture,pmatrix,psfrags,pspicture,smallmatrix,smallpmatrix,\
     tabular,tikzpicture,verbatim,vmatrix,wrapfigure";
 
+/// Maximum number of macro expansions per document, to stop runaway
+/// recursive definitions
+pub const MAX_MACRO_EXPANSIONS: usize = 100_000;
+
 /// Environment list separator
 pub const ENV_SEP: char = ',';
 
+/// Defaults implied by the name the program was invoked as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ModeDefaults {
+    /// Force LaTeX mode, as for `delatex`
+    pub latex: bool,
+}
+
+impl ModeDefaults {
+    /// Set these defaults in `opts`
+    pub fn apply(self, opts: &mut Options) {
+        if self.latex {
+            opts.latex = true;
+        }
+    }
+}
+
+/// Work out the mode from the program name (argv[0] without its directory).
+/// `delatex` is `detex -l`; a `.exe` suffix is ignored.
+pub fn mode_from_program_name(name: &str) -> ModeDefaults {
+    let name = name.strip_suffix(".exe").unwrap_or(name);
+    ModeDefaults {
+        latex: name == "delatex",
+    }
+}
+
+/// What a `~` tie becomes in the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TildeOutput {
+    /// An ordinary space, as in detex.l
+    #[default]
+    Space,
+    /// A no-break space, U+00A0
+    Nbsp,
+    /// The `~` itself
+    Literal,
+}
+
 /// Command-line options
 #[derive(Debug, Clone)]
 pub struct Options {
     /// Echo LaTeX \cite, \ref, and \pageref values
     pub cite: bool,
+    /// Without `cite`, write this in place of each dropped \cite
+    pub cite_placeholder: Option<String>,
     /// Force LaTeX mode
     pub latex: bool,
     /// Do not follow \input and \include
@@ -38,45 +85,209 @@ pub struct Options {
     pub space: bool,
     /// Force TeX mode (inhibit LaTeX mode)
     pub force_tex: bool,
+    /// Write en and em dashes, curly quotes and ellipses as Unicode
+    /// characters instead of ASCII "-", '"', "'" and "..."
+    pub unicode_punct: bool,
+    /// Collapse runs of spaces in the output into one
+    pub squeeze: bool,
+    /// Wrap output lines at word boundaries to this width
+    pub wrap_at: Option<usize>,
     /// Word-only output (one word per line)
     pub word: bool,
+    /// With `word`, count the words instead of printing them; see
+    /// `Detex::word_count`
+    pub count_only: bool,
     /// Output source location information
     pub src_loc: bool,
+    /// With `src_loc`, also output the column
+    pub src_loc_col: bool,
+    /// Record where each run of output comes from, see `Detex::source_map`
+    pub track_source_map: bool,
     /// Show picture names
     pub show_pictures: bool,
     /// Replace environments with "noun" for grammar checking
     pub replace: bool,
+    /// With `replace`, use this single word for all math instead of
+    /// `noun_text` and `verb_noun_text`
+    pub replace_with: Option<String>,
+    /// With `replace`, write words that vary with what the math holds,
+    /// such as "it" or "noun equals noun", instead of `noun_text` and
+    /// `verb_noun_text`
+    pub replace_grammar: bool,
+    /// Text that replaces math with `replace`
+    pub noun_text: String,
+    /// Text added after a verb symbol in math with `replace`
+    pub verb_noun_text: String,
+    /// Expand uses of parameterless \newcommand macros to their definitions
+    pub expand_macros: bool,
+    /// Drop the body of \def definitions, which detex.l keeps as text
+    pub skip_def_bodies: bool,
+    /// Keep the prose in \text and \mbox, \operatorname names and the
+    /// letters of \mathbb and similar commands inside math that is dropped
+    pub keep_math_text: bool,
+    /// With keep_math_text, also keep the letters of identifiers in that
+    /// math, each identifier as a word
+    pub keep_math_identifiers: bool,
+    /// Words written with keep_math_text for math alphabet letters, by
+    /// the command as written, e.g. ("\\mathbb{R}", "reals")
+    pub math_letter_names: Vec<(String, String)>,
     /// List of environments to ignore
     pub env_ignore: Vec<String>,
+    /// Environments that behave like a built-in one, e.g. a custom
+    /// "myverb" mapped to "verbatim"
+    pub env_aliases: HashMap<String, String>,
     /// List of includeonly files
     pub include_list: Vec<String>,
     /// Input search paths
     pub input_paths: Vec<String>,
+    /// Extensions tried, in order, for input files named without one
+    pub input_extensions: Vec<String>,
+    /// Work out the encoding of each input from a byte order mark, or
+    /// else whether it is valid UTF-8, falling back to Latin-1
+    pub detect_encoding: bool,
     /// Suppress warnings
     pub suppress_warnings: bool,
+    /// Write each output token as a JSON object {text, file, line, col}
+    /// on a line of its own. Not in detex.l.
+    pub json_lines: bool,
+    /// Warn about plain TeX constructs (\def, $$, \input without braces)
+    pub strict_latex: bool,
+    /// Commands, besides \verb, whose delimited argument is echoed
+    /// literally, e.g. a package's "lstinline"
+    pub verb_commands: Vec<String>,
+    /// Character shown for spaces in \verb*
+    pub visible_space: char,
+    /// What each `~` is written as
+    pub tilde_output: TildeOutput,
+    /// Only output text from these source lines (inclusive)
+    pub line_range: Option<(usize, usize)>,
+    /// Command line only, set by `from_args`: the file -o names for the
+    /// output
+    pub output_path: Option<String>,
+    /// Command line only: print a summary of warnings at the end
+    pub warn_summary: bool,
+    /// Command line only: print the title, authors, date, abstract,
+    /// sections and word count of each input as JSON instead of its text
+    pub summary_json: bool,
+    /// Command line only: -h or --help was given
+    pub show_help: bool,
+    /// Command line only: -v or --version was given
+    pub show_version: bool,
+    /// Command line only: an option that isn't recognized, to be reported
+    /// with the usage
+    pub unknown_option: Option<String>,
+    /// Threads `detex_files` uses, with the `parallel` feature; 0 means
+    /// one per CPU
+    pub jobs: usize,
 }
 
 impl Default for Options {
     fn default() -> Self {
         Self {
             cite: false,
+            cite_placeholder: None,
             latex: false,
             no_follow: false,
             space: false,
             force_tex: false,
+            unicode_punct: false,
+            squeeze: false,
+            wrap_at: None,
             word: false,
+            count_only: false,
             src_loc: false,
+            src_loc_col: false,
+            track_source_map: false,
             show_pictures: false,
             replace: false,
+            replace_with: None,
+            replace_grammar: false,
+            noun_text: "noun".to_string(),
+            verb_noun_text: " verbs noun".to_string(),
+            expand_macros: false,
+            skip_def_bodies: false,
+            keep_math_text: false,
+            keep_math_identifiers: false,
+            math_letter_names: Vec::new(),
             env_ignore: DEFAULT_ENV.split(ENV_SEP).map(String::from).collect(),
+            env_aliases: HashMap::new(),
             include_list: Vec::new(),
             input_paths: Vec::new(),
+            input_extensions: vec!["tex".to_string()],
+            detect_encoding: false,
             suppress_warnings: false,
+            json_lines: false,
+            strict_latex: false,
+            verb_commands: Vec::new(),
+            visible_space: '\u{2423}',
+            tilde_output: TildeOutput::Space,
+            line_range: None,
+            output_path: None,
+            warn_summary: false,
+            summary_json: false,
+            show_help: false,
+            show_version: false,
+            unknown_option: None,
+            jobs: 1,
         }
     }
 }
 
 impl Options {
+    /// Create options with LaTeX mode forced on or off
+    pub fn with_latex(mut self, latex: bool) -> Self {
+        self.latex = latex;
+        self
+    }
+
+    /// Create options with word-only output on or off
+    pub fn with_word(mut self, word: bool) -> Self {
+        self.word = word;
+        self
+    }
+
+    /// Create options that echo \cite, \ref and \pageref values or not
+    pub fn with_cite(mut self, cite: bool) -> Self {
+        self.cite = cite;
+        self
+    }
+
+    /// Create options that replace math with "noun" or not
+    pub fn with_replace(mut self, replace: bool) -> Self {
+        self.replace = replace;
+        self
+    }
+
+    /// Create options that replace control sequences with space or not
+    pub fn with_space(mut self, space: bool) -> Self {
+        self.space = space;
+        self
+    }
+
+    /// Create options that follow \input and \include or not
+    pub fn with_no_follow(mut self, no_follow: bool) -> Self {
+        self.no_follow = no_follow;
+        self
+    }
+
+    /// Create options that prefix lines with their source location or not
+    pub fn with_src_loc(mut self, src_loc: bool) -> Self {
+        self.src_loc = src_loc;
+        self
+    }
+
+    /// Create options that search these paths for input files
+    pub fn with_input_paths(mut self, paths: Vec<String>) -> Self {
+        self.input_paths = paths;
+        self
+    }
+
+    /// Create options that write each `~` as `tilde`
+    pub fn with_tilde_output(mut self, tilde: TildeOutput) -> Self {
+        self.tilde_output = tilde;
+        self
+    }
+
     /// Create options with custom environment ignore list
     pub fn with_env_ignore(mut self, env_list: &str) -> Self {
         self.env_ignore = env_list
@@ -87,6 +298,236 @@ impl Options {
         self
     }
 
+    /// Create options with custom text replacing math under `replace`
+    pub fn with_noun_text(mut self, text: &str) -> Self {
+        self.noun_text = text.to_string();
+        self
+    }
+
+    /// Create options with custom text added after verb symbols in math
+    /// under `replace`
+    pub fn with_verb_noun_text(mut self, text: &str) -> Self {
+        self.verb_noun_text = text.to_string();
+        self
+    }
+
+    /// Create options that write `name` for the math alphabet letter
+    /// `written` (e.g. `\mathbb{R}`) with `keep_math_text`
+    pub fn with_math_letter_name(mut self, written: &str, name: &str) -> Self {
+        self.math_letter_names
+            .push((written.to_string(), name.to_string()));
+        self
+    }
+
+    /// Create options that read `\name` like \verb
+    pub fn with_verb_command(mut self, name: &str) -> Self {
+        self.verb_commands.push(name.to_string());
+        self
+    }
+
+    /// Parse command-line arguments as the detex program does, returning
+    /// the options and the files to process. `args[0]` is the program
+    /// name, which selects LaTeX mode when it is `delatex`. Input paths are
+    /// set up from TEXINPUTS.
+    ///
+    /// -h, -v and an unknown option stop parsing and set `show_help`,
+    /// `show_version` or `unknown_option`. A missing or bad option value,
+    /// or an unreadable @listfile, is an error.
+    pub fn from_args(args: &[String]) -> Result<(Options, Vec<String>), String> {
+        let program_name = args
+            .first()
+            .and_then(|arg| Path::new(arg).file_name())
+            .and_then(|name| name.to_str())
+            .unwrap_or("detex");
+        let mut opts = Options::default();
+        mode_from_program_name(program_name).apply(&mut opts);
+        opts.setup_input_paths();
+
+        let mut files: Vec<String> = Vec::new();
+        let mut i = 1;
+
+        while i < args.len() {
+            let arg = &args[i];
+
+            if arg == "--" {
+                // Everything after "--" is a file name, even if it starts with a dash
+                files.extend(args[i + 1..].iter().cloned());
+                break;
+            } else if let Some(long) = arg.strip_prefix("--") {
+                // GNU-style long option, with its value after '=' or as the next argument
+                let (name, inline) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (long, None),
+                };
+                match name {
+                    "cite" => opts.cite = true,
+                    "environments" => match option_value(args, &mut i, inline) {
+                        Some(list) => opts = opts.with_env_ignore(&list),
+                        None => {
+                            return Err("--environments option requires an argument".to_string());
+                        }
+                    },
+                    "latex" => opts.latex = true,
+                    "tex" => opts.force_tex = true,
+                    "no-follow" => opts.no_follow = true,
+                    "replace" => opts.replace = true,
+                    "space" => opts.space = true,
+                    "word" => opts.word = true,
+                    "source-loc" => opts.src_loc = true,
+                    "version" => {
+                        opts.show_version = true;
+                        return Ok((opts, files));
+                    }
+                    "help" => {
+                        opts.show_help = true;
+                        return Ok((opts, files));
+                    }
+                    "warn-summary" => opts.warn_summary = true,
+                    "summary-json" => opts.summary_json = true,
+                    "json-lines" => opts.json_lines = true,
+                    "lines" => match option_value(args, &mut i, inline)
+                        .and_then(|range| parse_line_range(&range))
+                    {
+                        Some(range) => opts.line_range = Some(range),
+                        None => return Err("--lines option requires a range a:b".to_string()),
+                    },
+                    "max-line-length" => {
+                        match option_value(args, &mut i, inline)
+                            .and_then(|width| width.parse().ok())
+                        {
+                            Some(width) if width > 0 => opts.wrap_at = Some(width),
+                            _ => {
+                                return Err("--max-line-length option requires a width".to_string());
+                            }
+                        }
+                    }
+                    #[cfg(not(feature = "parallel"))]
+                    "jobs" => {
+                        return Err(
+                            "--jobs needs detex built with the parallel feature".to_string()
+                        );
+                    }
+                    #[cfg(feature = "parallel")]
+                    "jobs" => {
+                        match option_value(args, &mut i, inline).and_then(|jobs| jobs.parse().ok())
+                        {
+                            Some(jobs) => opts.jobs = jobs,
+                            None => return Err("--jobs option requires a number".to_string()),
+                        }
+                    }
+                    "squeeze" => opts.squeeze = true,
+                    "detect-encoding" => opts.detect_encoding = true,
+                    "output" => match option_value(args, &mut i, inline) {
+                        Some(path) => opts.output_path = Some(path),
+                        None => return Err("--output option requires a file name".to_string()),
+                    },
+                    "column" => {
+                        opts.src_loc = true;
+                        opts.src_loc_col = true;
+                    }
+                    "quiet" => opts.suppress_warnings = true,
+                    "replace-grammar" => {
+                        opts.replace = true;
+                        opts.replace_grammar = true;
+                    }
+                    "replace-with" => match option_value(args, &mut i, inline) {
+                        Some(word) => {
+                            opts.replace = true;
+                            opts.replace_with = Some(word);
+                        }
+                        None => {
+                            return Err("--replace-with option requires an argument".to_string());
+                        }
+                    },
+                    _ => {
+                        opts.unknown_option = Some(format!("--{}", name));
+                        return Ok((opts, files));
+                    }
+                }
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                let chars: Vec<char> = arg[1..].chars().collect();
+                let mut j = 0;
+
+                while j < chars.len() {
+                    match chars[j] {
+                        'c' => opts.cite = true,
+                        'e' => {
+                            i += 1;
+                            if i >= args.len() {
+                                return Err("-e option requires an argument".to_string());
+                            }
+                            opts = opts.with_env_ignore(&args[i]);
+                        }
+                        'l' => opts.latex = true,
+                        'o' => {
+                            i += 1;
+                            if i >= args.len() {
+                                return Err("-o option requires a file name".to_string());
+                            }
+                            opts.output_path = Some(args[i].clone());
+                        }
+                        #[cfg(not(feature = "parallel"))]
+                        'j' => {
+                            return Err(
+                                "-j needs detex built with the parallel feature".to_string()
+                            );
+                        }
+                        #[cfg(feature = "parallel")]
+                        'j' => {
+                            i += 1;
+                            match args.get(i).and_then(|jobs| jobs.parse().ok()) {
+                                Some(jobs) => opts.jobs = jobs,
+                                None => return Err("-j option requires a number".to_string()),
+                            }
+                        }
+                        'n' => opts.no_follow = true,
+                        'r' => opts.replace = true,
+                        's' => opts.space = true,
+                        't' => opts.force_tex = true,
+                        'w' => opts.word = true,
+                        'W' => {
+                            opts.word = true;
+                            opts.count_only = true;
+                        }
+                        '1' => opts.src_loc = true,
+                        'Q' => opts.suppress_warnings = true,
+                        'v' => {
+                            opts.show_version = true;
+                            return Ok((opts, files));
+                        }
+                        'h' | '?' => {
+                            opts.show_help = true;
+                            return Ok((opts, files));
+                        }
+                        c => {
+                            opts.unknown_option = Some(format!("-{}", c));
+                            return Ok((opts, files));
+                        }
+                    }
+                    j += 1;
+                }
+            } else if let Some(list) = arg.strip_prefix('@').filter(|list| !list.is_empty()) {
+                // @listfile names the files to process, one per line
+                match fs::read_to_string(list) {
+                    Ok(contents) => files.extend(
+                        contents
+                            .lines()
+                            .map(str::trim)
+                            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                            .map(String::from),
+                    ),
+                    Err(e) => return Err(format!("can't read {}: {}", list, e)),
+                }
+            } else {
+                files.push(arg.clone());
+            }
+
+            i += 1;
+        }
+
+        Ok((opts, files))
+    }
+
     /// Set up input paths from environment or defaults
     pub fn setup_input_paths(&mut self) {
         let texinputs = std::env::var("TEXINPUTS").unwrap_or_else(|_| DEFAULT_INPUTS.to_string());
@@ -116,3 +557,22 @@ impl Options {
         self.latex && !self.force_tex
     }
 }
+
+/// Value of a long option: the text after '=', or else the next argument
+fn option_value(args: &[String], i: &mut usize, inline: Option<&str>) -> Option<String> {
+    match inline {
+        Some(value) => Some(value.to_string()),
+        None => {
+            *i += 1;
+            args.get(*i).cloned()
+        }
+    }
+}
+
+/// Parse a --lines range such as "10:20"
+fn parse_line_range(range: &str) -> Option<(usize, usize)> {
+    let (first, last) = range.split_once(':')?;
+    let first = first.parse().ok()?;
+    let last = last.parse().ok()?;
+    (first <= last).then_some((first, last))
+}
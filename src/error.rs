@@ -0,0 +1,47 @@
+//! Error type for detex processing.
+
+use std::fmt;
+use std::io;
+
+/// Errors that stop processing of an input.
+///
+/// The `Display` output matches the messages printed by opendetex, with
+/// the line number added for an unterminated \verb.
+#[derive(Debug)]
+pub enum DetexError {
+    /// Reading an input failed part way through
+    Io { path: String, source: io::Error },
+    /// A file named on the command line could not be opened
+    FileNotFound(String),
+    /// A \verb was not closed before the end of the line or file
+    UnterminatedVerb { line: usize },
+    /// Too many nested \input or \include files
+    FileStackOverflow(String),
+    /// An \input or \include of a file that is already open
+    IncludeCycle(String),
+}
+
+impl fmt::Display for DetexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DetexError::Io { path, source } => write!(f, "error reading {}: {}", path, source),
+            DetexError::FileNotFound(path) => write!(f, "can't open file {}", path),
+            DetexError::UnterminatedVerb { line } => {
+                write!(f, "\\verb not complete before eof (line {})", line)
+            }
+            DetexError::FileStackOverflow(path) => {
+                write!(f, "file stack overflow, ignoring {}", path)
+            }
+            DetexError::IncludeCycle(path) => write!(f, "include cycle detected: {}", path),
+        }
+    }
+}
+
+impl std::error::Error for DetexError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DetexError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
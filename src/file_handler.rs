@@ -1,10 +1,30 @@
 //! File handling for detex - opening files with TEXINPUTS search.
 
+use std::collections::VecDeque;
 use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
 use std::path::{Path, PathBuf};
 
 use crate::config::Options;
 
+/// Finds and opens the files named on the command line and in \input,
+/// \include and \subfile, so that a project can be read from somewhere
+/// other than the filesystem, such as memory or an archive.
+pub trait FileResolver {
+    /// Open the file `name`, returning a reader and the path it was found at
+    fn resolve(&self, name: &str, opts: &Options) -> Option<(Box<dyn Read>, String)>;
+}
+
+/// The default resolver, which searches the filesystem with `tex_open`
+pub struct FsResolver;
+
+impl FileResolver for FsResolver {
+    fn resolve(&self, name: &str, opts: &Options) -> Option<(Box<dyn Read>, String)> {
+        let (file, path) = tex_open(name, opts)?;
+        Some((Box::new(file), path.to_string_lossy().into_owned()))
+    }
+}
+
 /// Try to open a TeX file, searching through input paths.
 ///
 /// For each input path the following order is used:
@@ -13,6 +33,9 @@ use crate::config::Options;
 /// - file     - base name, add .tex and try it
 /// - file     - try it as is
 ///
+/// The `.tex` extension above stands for each entry of
+/// `opts.input_extensions`, tried in order.
+///
 /// If the file begins with '/', no paths are searched.
 pub fn tex_open(filename: &str, opts: &Options) -> Option<(File, PathBuf)> {
     let path = Path::new(filename);
@@ -22,12 +45,17 @@ pub fn tex_open(filename: &str, opts: &Options) -> Option<(File, PathBuf)> {
         return try_open_file(path);
     }
 
+    let has_input_extension = opts
+        .input_extensions
+        .iter()
+        .any(|ext| filename.ends_with(&format!(".{}", ext)));
+
     // Search through input paths
     for input_path in &opts.input_paths {
         let full_path = Path::new(input_path).join(filename);
 
         // If filename ends in .tex, it must be exactly that
-        if filename.ends_with(".tex") {
+        if has_input_extension {
             if let Some(result) = try_open_file(&full_path) {
                 return Some(result);
             }
@@ -43,8 +71,7 @@ pub fn tex_open(filename: &str, opts: &Options) -> Option<(File, PathBuf)> {
         }
 
         // Try adding .tex extension
-        let tex_path = full_path.with_extension("tex");
-        if let Some(result) = try_open_file(&tex_path) {
+        if let Some(result) = try_extensions(&full_path, opts) {
             return Some(result);
         }
 
@@ -60,8 +87,7 @@ pub fn tex_open(filename: &str, opts: &Options) -> Option<(File, PathBuf)> {
             return Some(result);
         }
 
-        let tex_path = path.with_extension("tex");
-        if let Some(result) = try_open_file(&tex_path) {
+        if let Some(result) = try_extensions(path, opts) {
             return Some(result);
         }
     }
@@ -69,10 +95,23 @@ pub fn tex_open(filename: &str, opts: &Options) -> Option<(File, PathBuf)> {
     None
 }
 
+/// Try each of the input extensions in turn
+fn try_extensions(path: &Path, opts: &Options) -> Option<(File, PathBuf)> {
+    opts.input_extensions
+        .iter()
+        .find_map(|ext| try_open_file(&path.with_extension(ext)))
+}
+
 fn try_open_file(path: &Path) -> Option<(File, PathBuf)> {
     File::open(path).ok().map(|f| (f, path.to_path_buf()))
 }
 
+/// The canonical form of a path found by a resolver, or the path as given
+/// if it isn't on the filesystem
+pub fn canonical_path(path: &str) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
 /// Check if a file is in the includeonly list.
 /// If there is no list, all files are considered "in the list".
 pub fn in_include_list(filename: &str, opts: &Options) -> bool {
@@ -88,51 +127,191 @@ pub fn in_include_list(filename: &str, opts: &Options) -> bool {
     opts.include_list.iter().any(|inc| inc == base)
 }
 
-/// A buffered character source that supports pushback and line tracking
-pub struct CharSource {
-    buffer: Vec<char>,
-    pos: usize,
-    pushback: Vec<char>,
+/// A reader that transcodes its input to UTF-8, for `Options::detect_encoding`.
+///
+/// The whole input is read on the first read, since telling UTF-8 from
+/// Latin-1 needs all of it.
+struct DetectEncoding<R> {
+    inner: Option<R>,
+    decoded: Cursor<Vec<u8>>,
+}
+
+impl<R: Read> Read for DetectEncoding<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(mut inner) = self.inner.take() {
+            let mut bytes = Vec::new();
+            inner.read_to_end(&mut bytes)?;
+            self.decoded = Cursor::new(decode_bytes(&bytes).into_bytes());
+        }
+        self.decoded.read(buf)
+    }
+}
+
+/// Decode input of unknown encoding: UTF-8 or UTF-16 with a byte order
+/// mark, otherwise UTF-8 if it is valid and Latin-1 if not
+fn decode_bytes(bytes: &[u8]) -> String {
+    let utf16 = |units: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units = units
+            .chunks_exact(2)
+            .map(|pair| from_bytes([pair[0], pair[1]]));
+        char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    };
+    if let Some(rest) = bytes.strip_prefix(b"\xef\xbb\xbf") {
+        String::from_utf8_lossy(rest).into_owned()
+    } else if let Some(rest) = bytes.strip_prefix(b"\xff\xfe") {
+        utf16(rest, u16::from_le_bytes)
+    } else if let Some(rest) = bytes.strip_prefix(b"\xfe\xff") {
+        utf16(rest, u16::from_be_bytes)
+    } else {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) => bytes.iter().map(|&b| char::from(b)).collect(),
+        }
+    }
+}
+
+/// A streaming character source that supports pushback and line tracking.
+///
+/// Input is read through a `BufReader` and decoded as UTF-8 on demand, so
+/// only a small window of lookahead is held in memory at any time.
+pub struct CharSource<'a> {
+    reader: BufReader<Box<dyn Read + 'a>>,
+    /// Decoded characters not yet consumed, including pushed-back ones
+    pending: VecDeque<char>,
+    /// Trailing bytes of an incomplete UTF-8 sequence from the last read
+    partial: Vec<u8>,
+    /// The last character decoded was a \r, so a \n after it is dropped
+    after_cr: bool,
+    /// Set once the reader is exhausted or has failed
+    exhausted: bool,
+    error: Option<io::Error>,
     pub line: usize,
     pub at_line_start: bool,
+    /// Characters consumed on the current line
+    pub column: usize,
+    /// Length of the previous line, restored when its newline is pushed back
+    prev_line_len: usize,
 }
 
-impl CharSource {
-    pub fn new(content: String) -> Self {
+impl<'a> CharSource<'a> {
+    /// A source for an input, transcoded to UTF-8 first with
+    /// `opts.detect_encoding`
+    pub fn for_input<R: Read + 'a>(reader: R, opts: &Options) -> Self {
+        if opts.detect_encoding {
+            Self::new(DetectEncoding {
+                inner: Some(reader),
+                decoded: Cursor::default(),
+            })
+        } else {
+            Self::new(reader)
+        }
+    }
+
+    pub fn new<R: Read + 'a>(reader: R) -> Self {
         Self {
-            buffer: content.chars().collect(),
-            pos: 0,
-            pushback: Vec::new(),
+            reader: BufReader::new(Box::new(reader)),
+            pending: VecDeque::new(),
+            partial: Vec::new(),
+            after_cr: false,
+            exhausted: false,
+            error: None,
             line: 1,
             at_line_start: true,
+            column: 0,
+            prev_line_len: 0,
         }
     }
 
-    pub fn peek(&self) -> Option<char> {
-        if let Some(&c) = self.pushback.last() {
-            Some(c)
-        } else if self.pos < self.buffer.len() {
-            Some(self.buffer[self.pos])
-        } else {
-            None
+    /// Decode more input until at least `n` characters are pending or the
+    /// reader is exhausted.
+    fn fill(&mut self, n: usize) {
+        while self.pending.len() < n && !self.exhausted {
+            let buf = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.fail(e);
+                    break;
+                }
+            };
+
+            if buf.is_empty() {
+                self.exhausted = true;
+                if !self.partial.is_empty() {
+                    self.fail(invalid_utf8());
+                }
+                break;
+            }
+
+            // Prepend any incomplete sequence left over from the last chunk
+            let mut bytes = std::mem::take(&mut self.partial);
+            bytes.extend_from_slice(buf);
+            let consumed = buf.len();
+            self.reader.consume(consumed);
+
+            match std::str::from_utf8(&bytes) {
+                Ok(text) => self.push_decoded(text),
+                Err(e) => {
+                    let (valid, rest) = bytes.split_at(e.valid_up_to());
+                    // valid_up_to() guarantees this prefix decodes
+                    if let Ok(text) = std::str::from_utf8(valid) {
+                        self.push_decoded(text);
+                    }
+                    if e.error_len().is_some() {
+                        self.fail(invalid_utf8());
+                    } else {
+                        // Sequence is cut off at the chunk boundary
+                        self.partial = rest.to_vec();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queue decoded text, turning \r\n and lone \r line endings into \n.
+    /// Not in detex.l, which echoes the \r.
+    fn push_decoded(&mut self, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\r' => self.pending.push_back('\n'),
+                '\n' if self.after_cr => {}
+                c => self.pending.push_back(c),
+            }
+            self.after_cr = c == '\r';
         }
     }
 
+    fn fail(&mut self, error: io::Error) {
+        self.exhausted = true;
+        self.error = Some(error);
+    }
+
+    /// Take the error that stopped reading early, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    pub fn peek(&mut self) -> Option<char> {
+        self.fill(1);
+        self.pending.front().copied()
+    }
+
     pub fn next(&mut self) -> Option<char> {
-        let c = if let Some(c) = self.pushback.pop() {
-            c
-        } else if self.pos < self.buffer.len() {
-            let c = self.buffer[self.pos];
-            self.pos += 1;
-            c
-        } else {
-            return None;
-        };
+        self.fill(1);
+        let c = self.pending.pop_front()?;
 
         // Note: line number is NOT automatically incremented here.
         // The lexer is responsible for calling incr_line() when appropriate.
         // See detex.l:743-751 IncrLineNo() and line 722 LineBreak()
         self.at_line_start = c == '\n';
+        if c == '\n' {
+            self.prev_line_len = self.column;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
 
         Some(c)
     }
@@ -148,37 +327,28 @@ impl CharSource {
         if c == '\n' {
             self.line = self.line.saturating_sub(1);
             self.at_line_start = false;
+            self.column = self.prev_line_len;
+        } else {
+            self.column = self.column.saturating_sub(1);
         }
-        self.pushback.push(c);
+        self.pending.push_front(c);
     }
 
-    pub fn is_eof(&self) -> bool {
-        self.pushback.is_empty() && self.pos >= self.buffer.len()
+    pub fn is_eof(&mut self) -> bool {
+        self.fill(1);
+        self.pending.is_empty()
     }
 
     /// Peek ahead at the next n characters without consuming them
-    pub fn peek_ahead(&self, n: usize) -> String {
-        let mut result = String::new();
-        let mut pushback_used = 0;
-
-        // First consume from pushback (in reverse order)
-        for i in (0..self.pushback.len()).rev() {
-            if result.len() >= n {
-                break;
-            }
-            result.push(self.pushback[self.pushback.len() - 1 - i]);
-            pushback_used += 1;
-        }
-
-        // Then from buffer
-        let start_pos = self.pos;
-        for i in 0..(n - pushback_used) {
-            if start_pos + i >= self.buffer.len() {
-                break;
-            }
-            result.push(self.buffer[start_pos + i]);
-        }
-
-        result
+    pub fn peek_ahead(&mut self, n: usize) -> String {
+        self.fill(n);
+        self.pending.iter().take(n).collect()
     }
 }
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+    )
+}
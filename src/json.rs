@@ -0,0 +1,142 @@
+//! JSON output for --json-lines and --summary-json.
+//!
+//! Not in detex.l. The few JSON values needed are written by hand rather
+//! than through a serialization crate.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::lexer::ExtractResult;
+
+/// Quote `s` as a JSON string
+pub(crate) fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The writer the lexer's output goes through.
+///
+/// Output passes through unchanged unless `Options::json_lines` is set.
+/// Then each whitespace-separated token is written as a JSON object
+/// `{"text", "file", "line", "col"}` on a line of its own as soon as the
+/// whitespace after it, or a flush at the end of an input, is reached.
+pub(crate) struct TokenWriter<W: Write> {
+    inner: W,
+    json_lines: bool,
+    /// Input position of the next character written
+    file: String,
+    line: usize,
+    column: usize,
+    /// The token being collected and the position of its first character
+    token: String,
+    token_file: String,
+    token_line: usize,
+    token_column: usize,
+}
+
+impl<W: Write> TokenWriter<W> {
+    pub fn new(inner: W, json_lines: bool) -> Self {
+        Self {
+            inner,
+            json_lines,
+            file: String::new(),
+            line: 1,
+            column: 1,
+            token: String::new(),
+            token_file: String::new(),
+            token_line: 1,
+            token_column: 1,
+        }
+    }
+
+    /// Place the output written from now on at `column` of `line` in
+    /// `file`. Characters after the first move along the line from there.
+    pub fn set_position(&mut self, file: &str, line: usize, column: usize) {
+        if self.file != file {
+            self.file = file.to_string();
+        }
+        self.line = line;
+        self.column = column;
+    }
+
+    /// Write the token collected so far, if any
+    fn end_token(&mut self) -> io::Result<()> {
+        if self.token.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            self.inner,
+            "{{\"text\":{},\"file\":{},\"line\":{},\"col\":{}}}",
+            quote(&self.token),
+            quote(&self.token_file),
+            self.token_line,
+            self.token_column
+        )?;
+        self.token.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for TokenWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.json_lines {
+            return self.inner.write(buf);
+        }
+        // The lexer writes whole characters, so each write decodes alone
+        for c in String::from_utf8_lossy(buf).chars() {
+            if c.is_whitespace() {
+                self.end_token()?;
+            } else {
+                if self.token.is_empty() {
+                    self.token_file.clone_from(&self.file);
+                    self.token_line = self.line;
+                    self.token_column = self.column;
+                }
+                self.token.push(c);
+            }
+            self.column += 1;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.end_token()?;
+        self.inner.flush()
+    }
+}
+
+/// The --summary-json object for an `extract` result: its title, authors,
+/// date, abstract, sections and word count on one line
+pub fn summary_json(result: &ExtractResult) -> String {
+    let optional = |text: &Option<String>| text.as_deref().map_or("null".to_string(), quote);
+    let authors: Vec<String> = result.authors.iter().map(|author| quote(author)).collect();
+    let sections: Vec<String> = result
+        .sections
+        .iter()
+        .map(|(level, title)| format!("{{\"level\":{},\"title\":{}}}", level, quote(title)))
+        .collect();
+    format!(
+        "{{\"title\":{},\"authors\":[{}],\"date\":{},\"abstract\":{},\"sections\":[{}],\"word_count\":{}}}\n",
+        optional(&result.title),
+        authors.join(","),
+        optional(&result.date),
+        optional(&result.abstract_text),
+        sections.join(","),
+        result.word_count
+    )
+}
@@ -26,10 +26,16 @@
 
 #![allow(clippy::single_match)]
 
+use std::collections::{HashMap, HashSet};
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
-use crate::config::{MAX_FILE_STACK, Options};
-use crate::file_handler::{CharSource, in_include_list, tex_open};
+use crate::accents::{MAX_ACCENT_LEN, is_combining_mark, parse_accent};
+use crate::config::{MAX_FILE_STACK, MAX_MACRO_EXPANSIONS, Options, TildeOutput};
+use crate::error::DetexError;
+use crate::file_handler::{CharSource, FileResolver, FsResolver, canonical_path, in_include_list};
+use crate::json::TokenWriter;
+use crate::output::OutputWriter;
 
 /// Lexer states matching the original flex states.
 /// See detex.l lines 206-209:
@@ -62,82 +68,312 @@ enum State {
     LaOptArg2,   // detex.l:513-514 - inside optional [...] arg for LaMacro2
     LaVerbatim,  // detex.l:225-227 - inside verbatim environment, echoing content
     LaPicture,   // detex.l:300-303 - parsing \includegraphics{...}
+    LaComment,   // Not in detex.l - inside a comment environment, discarding content
+}
+
+impl State {
+    /// Name of the state, as in the list above
+    #[cfg(feature = "test-support")]
+    fn name(self) -> &'static str {
+        match self {
+            State::Normal => "Normal",
+            State::Define => "Define",
+            State::Display => "Display",
+            State::IncludeOnly => "IncludeOnly",
+            State::Input => "Input",
+            State::Math => "Math",
+            State::Control => "Control",
+            State::LaDisplay => "LaDisplay",
+            State::LaEnd => "LaEnd",
+            State::LaEnv => "LaEnv",
+            State::LaFormula => "LaFormula",
+            State::LaInclude => "LaInclude",
+            State::LaMacro => "LaMacro",
+            State::LaOptArg => "LaOptArg",
+            State::LaMacro2 => "LaMacro2",
+            State::LaOptArg2 => "LaOptArg2",
+            State::LaVerbatim => "LaVerbatim",
+            State::LaPicture => "LaPicture",
+            State::LaComment => "LaComment",
+        }
+    }
+}
+
+/// A run of output that comes from consecutive characters of one source
+/// line, recorded with `Options::track_source_map`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpan {
+    /// Byte offset of the run in the output
+    pub start: usize,
+    /// Length of the run in bytes
+    pub len: usize,
+    /// File the run comes from, as named on the command line or in \input
+    pub file: String,
+    /// Source line, counted as for the -1 prefix
+    pub line: usize,
+    /// Column of the first source character, counting from 1
+    pub column: usize,
+}
+
+/// Text of a document and the metadata `extract` gathers from it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractResult {
+    /// The text, as `detex_str` gives it
+    pub text: String,
+    /// Text of the \title
+    pub title: Option<String>,
+    /// Authors in \author, which are separated by \and, without \thanks
+    pub authors: Vec<String>,
+    /// Text of the \date
+    pub date: Option<String>,
+    /// Text of the abstract environment
+    pub abstract_text: Option<String>,
+    /// Sectioning commands in order, with their depth (0 for \part up to
+    /// 6 for \subparagraph) and title
+    pub sections: Vec<(u8, String)>,
+    /// Keys of \label
+    pub labels: Vec<String>,
+    /// Keys cited with \cite or a natbib or biblatex form such as \citep
+    /// or \parencite, in order and with repeats
+    pub citations: Vec<String>,
+    /// Number of words in the text, split as -w splits them
+    pub word_count: usize,
 }
 
 /// File context for stack
-struct FileContext {
-    source: CharSource,
+struct FileContext<'a> {
+    source: CharSource<'a>,
     name: String,
+    /// Canonical path of the file, to detect \input cycles
+    path: Option<PathBuf>,
+}
+
+/// What the math being replaced with `Options::replace_grammar` holds
+#[derive(Default)]
+struct MathShape {
+    relations: usize,
+    operators: usize,
+}
+
+impl MathShape {
+    /// Words that stand for the math: a relation reads as "noun equals
+    /// noun", arithmetic as "the expression", and anything else, such as a
+    /// single variable, as "it"
+    fn replacement(&self) -> &'static str {
+        if self.relations > 0 {
+            "noun equals noun"
+        } else if self.operators > 0 {
+            "the expression"
+        } else {
+            "it"
+        }
+    }
 }
 
 /// The main detex processor
-pub struct Detex<W: Write> {
+pub struct Detex<'a, W: Write> {
     opts: Options,
     state: State,
-    output: W,
-    file_stack: Vec<FileContext>,
+    output: OutputWriter<TokenWriter<W>>,
+    file_stack: Vec<FileContext<'a>>,
+    /// Opens files, on the filesystem unless set with `with_resolver`
+    resolver: Box<dyn FileResolver>,
     current_ignored_env: String,
+    /// Nesting depth of environments with the same name as the ignored one
+    ignored_env_depth: usize,
     open_braces: usize,
     args_count: usize,
     current_braces_level: usize,
     footnote_level: i32,
+    /// Brace level at which a \caption argument closes, ending its line
+    caption_level: Option<usize>,
+    /// Parameterless macros recorded with expand_macros, by name
+    macros: HashMap<String, String>,
+    /// Macro expansions so far, limited to MAX_MACRO_EXPANSIONS
+    macro_expansions: usize,
+    /// Environment whose \end closes the LaVerbatim or LaComment state
+    verbatim_env: String,
+    /// Brace level of the [label] of an \item, ended by the next ']' at
+    /// that level
+    item_label_level: Option<usize>,
+    /// Open tabular-like environments that are not ignored
+    table_depth: usize,
+    /// A \\ row break or a rule in a table, or the end of a caption, has
+    /// ended the output line, so the source newline after it is not
+    /// printed again
+    line_ended: bool,
+    /// Brace level at which prose inside math (\intertext, or \text with
+    /// keep_math_text) closes, and the state to return to
+    prose_end: Option<(usize, State)>,
+    /// The math open with replace_grammar, written when it closes
+    math_shape: Option<MathShape>,
+    /// With keep_math_identifiers, whether the last math character was a
+    /// letter of an identifier, and whether one has ended since, so the
+    /// next needs a space before it
+    in_math_identifier: bool,
+    math_identifier_ended: bool,
+    /// Metadata gathered for `extract`, which sets it
+    metadata: Option<ExtractResult>,
     at_column_zero: bool,
+    warnings: Vec<String>,
+    /// Words seen with count_only, and their distinct lowercase forms
+    word_count: usize,
+    unique_words: HashSet<String>,
+    source_map: Vec<SourceSpan>,
+    /// Column of the first character of the token being processed
+    token_column: usize,
 }
 
-impl<W: Write> Detex<W> {
+impl<'a, W: Write> Detex<'a, W> {
     pub fn new(opts: Options, output: W) -> Self {
+        let mut writer = OutputWriter::new(
+            TokenWriter::new(output, opts.json_lines),
+            opts.squeeze,
+            opts.wrap_at,
+        );
+        // Not in detex.l: with count_only nothing but the counts is kept
+        writer.set_discard(opts.count_only);
         Self {
             opts,
             state: State::Normal,
-            output,
+            output: writer,
             file_stack: Vec::with_capacity(MAX_FILE_STACK),
+            resolver: Box::new(FsResolver),
             current_ignored_env: String::new(),
+            ignored_env_depth: 0,
             open_braces: 0,
             args_count: 0,
             current_braces_level: 0,
             footnote_level: -100,
+            caption_level: None,
+            prose_end: None,
+            math_shape: None,
+            in_math_identifier: false,
+            math_identifier_ended: false,
+            metadata: None,
+            macros: HashMap::new(),
+            macro_expansions: 0,
+            verbatim_env: String::new(),
+            item_label_level: None,
+            table_depth: 0,
+            line_ended: false,
             at_column_zero: true,
+            warnings: Vec::new(),
+            word_count: 0,
+            unique_words: HashSet::new(),
+            source_map: Vec::new(),
+            token_column: 1,
         }
     }
 
-    /// Process a file
-    pub fn process_file(&mut self, filename: &str) -> Result<(), String> {
-        let (mut file, _path) = tex_open(filename, &self.opts)
-            .ok_or_else(|| format!("can't open file {}", filename))?;
+    /// Open files with `resolver` instead of searching the filesystem
+    pub fn with_resolver(mut self, resolver: impl FileResolver + 'static) -> Self {
+        self.resolver = Box::new(resolver);
+        self
+    }
 
-        let mut content = String::new();
-        file.read_to_string(&mut content)
-            .map_err(|e| format!("error reading {}: {}", filename, e))?;
+    /// Warnings issued while processing, in the order they occurred
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
 
-        let source = CharSource::new(content);
-        let name = filename.to_string();
+    /// Spans mapping the output back to the source, recorded with
+    /// `track_source_map`
+    pub fn source_map(&self) -> &[SourceSpan] {
+        &self.source_map
+    }
 
-        self.file_stack.push(FileContext { source, name });
-        self.state = State::Normal;
+    /// Number of words counted with `count_only`
+    pub fn word_count(&self) -> usize {
+        self.word_count
+    }
+
+    /// Number of distinct words, ignoring case, counted with `count_only`
+    pub fn unique_word_count(&self) -> usize {
+        self.unique_words.len()
+    }
+
+    /// Process a file
+    pub fn process_file(&mut self, filename: &str) -> Result<(), DetexError> {
+        let (file, path) = self
+            .resolver
+            .resolve(filename, &self.opts)
+            .ok_or_else(|| DetexError::FileNotFound(filename.to_string()))?;
+        self.reset();
+        self.file_stack.push(FileContext {
+            source: CharSource::for_input(file, &self.opts),
+            name: filename.to_string(),
+            path: Some(canonical_path(&path)),
+        });
         self.process()
     }
 
     /// Process stdin
-    pub fn process_stdin(&mut self) -> Result<(), String> {
-        let mut content = String::new();
-        std::io::stdin()
-            .read_to_string(&mut content)
-            .map_err(|e| format!("error reading stdin: {}", e))?;
+    pub fn process_stdin(&mut self) -> Result<(), DetexError> {
+        self.process_reader(std::io::stdin(), "<stdin>")
+    }
 
-        let source = CharSource::new(content);
+    /// Process input from an arbitrary reader.
+    ///
+    /// `name` is used for the -1 prefix and in error messages. The input is
+    /// streamed rather than read into memory up front.
+    pub fn process_reader<R: Read + 'a>(
+        &mut self,
+        reader: R,
+        name: &str,
+    ) -> Result<(), DetexError> {
+        let source = CharSource::for_input(reader, &self.opts);
+        self.reset();
         self.file_stack.push(FileContext {
             source,
-            name: "<stdin>".to_string(),
+            name: name.to_string(),
+            path: None,
         });
-        self.state = State::Normal;
         self.process()
     }
 
-    fn current_source(&self) -> Option<&CharSource> {
+    /// Restore the lexer to its initial state, so that whatever one input
+    /// leaves open (a brace, a footnote, math, an ignored environment)
+    /// doesn't carry over into the next. Called at the start of each
+    /// input. The options, including a LaTeX mode detected from an earlier
+    /// input as in detex.l, are kept, as are the warnings, word counts and
+    /// source map collected so far.
+    pub fn reset(&mut self) {
+        self.state = State::Normal;
+        self.file_stack.clear();
+        self.current_ignored_env.clear();
+        self.ignored_env_depth = 0;
+        self.open_braces = 0;
+        self.args_count = 0;
+        self.current_braces_level = 0;
+        self.footnote_level = -100;
+        self.caption_level = None;
+        self.macros.clear();
+        self.macro_expansions = 0;
+        self.verbatim_env.clear();
+        self.item_label_level = None;
+        self.table_depth = 0;
+        self.line_ended = false;
+        self.prose_end = None;
+        self.math_shape = None;
+        self.in_math_identifier = false;
+        self.math_identifier_ended = false;
+        self.at_column_zero = true;
+        self.token_column = 1;
+    }
+
+    /// Name of the current lexer state, so tests can check that a document
+    /// leaves the processor back in `Normal`
+    #[cfg(feature = "test-support")]
+    pub fn current_state_name(&self) -> &'static str {
+        self.state.name()
+    }
+
+    fn current_source(&self) -> Option<&CharSource<'a>> {
         self.file_stack.last().map(|ctx| &ctx.source)
     }
 
-    fn current_source_mut(&mut self) -> Option<&mut CharSource> {
+    fn current_source_mut(&mut self) -> Option<&mut CharSource<'a>> {
         self.file_stack.last_mut().map(|ctx| &mut ctx.source)
     }
 
@@ -153,19 +389,98 @@ impl<W: Write> Detex<W> {
     }
 
     /// Main processing loop
-    fn process(&mut self) -> Result<(), String> {
+    fn process(&mut self) -> Result<(), DetexError> {
         while !self.file_stack.is_empty() {
-            if self.current_source().map(|s| s.is_eof()).unwrap_or(true) {
-                self.file_stack.pop();
+            if self
+                .current_source_mut()
+                .map(|s| s.is_eof())
+                .unwrap_or(true)
+            {
+                let mut ctx = self.file_stack.pop().unwrap();
+                if let Some(e) = ctx.source.take_error() {
+                    // Errors in the outermost file are fatal, like a failed
+                    // open; errors in included files are only warnings.
+                    if self.file_stack.is_empty() {
+                        return Err(DetexError::Io {
+                            path: ctx.name,
+                            source: e,
+                        });
+                    }
+                    self.warn(format!("can't read file {}: {}", ctx.name, e));
+                }
+                if self.file_stack.is_empty()
+                    && matches!(self.state, State::LaEnv | State::LaEnd | State::LaComment)
+                {
+                    let env = if self.state == State::LaComment {
+                        "comment".to_string()
+                    } else {
+                        std::mem::take(&mut self.current_ignored_env)
+                    };
+                    self.warn(format!("environment '{}' not closed before eof", env));
+                    self.state = State::Normal;
+                }
                 continue;
             }
-            self.process_next()?;
+            if let Some((first, last)) = self.opts.line_range {
+                // Lines are counted in the file named on the command line;
+                // state is still tracked outside the range, only output is
+                // dropped.
+                let line = self.file_stack[0].source.line;
+                self.output.set_muted(line < first || line > last);
+            }
+            self.token_column = self.current_source().map_or(1, |s| s.column + 1);
+            if self.opts.json_lines
+                && let Some(ctx) = self.file_stack.last()
+            {
+                self.output
+                    .get_mut()
+                    .set_position(&ctx.name, ctx.source.line, self.token_column);
+            }
+            if self.opts.track_source_map {
+                self.process_next_mapped()?;
+            } else {
+                self.process_next()?;
+            }
+        }
+        // Write out the last wrapped word or --json-lines token
+        let _ = self.output.flush();
+        Ok(())
+    }
+
+    /// Process the next token, recording the output it produces as coming
+    /// from the position of its first character
+    fn process_next_mapped(&mut self) -> Result<(), DetexError> {
+        let start = self.output.position();
+        let file = self.current_filename().to_string();
+        let line = self.current_line();
+        let column = self.token_column;
+        self.process_next()?;
+        let len = self.output.position() - start;
+        if len == 0 {
+            return Ok(());
+        }
+        if let Some(last) = self.source_map.last_mut()
+            && last.start + last.len == start
+            && last.file == file
+            && last.line == line
+            && last.column + last.len == column
+        {
+            last.len += len;
+        } else {
+            self.source_map.push(SourceSpan {
+                start,
+                len,
+                file,
+                line,
+                column,
+            });
         }
         Ok(())
     }
 
     /// Process next token based on current state
-    fn process_next(&mut self) -> Result<(), String> {
+    fn process_next(&mut self) -> Result<(), DetexError> {
+        let _ = self.output.set_verbatim(self.state == State::LaVerbatim);
         match self.state {
             State::Normal => self.process_normal(),
             State::Define => self.process_define(),
@@ -185,6 +500,7 @@ impl<W: Write> Detex<W> {
             State::LaOptArg2 => self.process_la_opt_arg2(),
             State::LaVerbatim => self.process_la_verbatim(),
             State::LaPicture => self.process_la_picture(),
+            State::LaComment => self.process_la_comment(),
         }
     }
 
@@ -208,7 +524,12 @@ impl<W: Write> Detex<W> {
         if self.opts.src_loc && self.at_column_zero {
             let filename = self.current_filename().to_string();
             let line = self.current_line();
-            let _ = write!(self.output, "{}:{}: ", filename, line);
+            if self.opts.src_loc_col {
+                let _ = write!(self.output, "{}:{}:{}: ", filename, line, self.token_column);
+            } else {
+                let _ = write!(self.output, "{}:{}: ", filename, line);
+            }
+            self.output.reset_line();
             self.at_column_zero = false;
         }
     }
@@ -240,6 +561,40 @@ impl<W: Write> Detex<W> {
         self.at_column_zero = true;
     }
 
+    /// Write a quote converted from TeX ligatures, in ASCII as detex.l does
+    /// or as a curly quote with unicode_punct
+    fn write_quote(&mut self, ascii: &str, unicode: &str) {
+        let quote = if self.opts.unicode_punct {
+            unicode
+        } else {
+            ascii
+        };
+        let _ = write!(self.output, "{}", quote);
+    }
+
+    /// Not in detex.l: start a new output line for a command such as \par,
+    /// without counting a source line as newline() does
+    fn line_break(&mut self) {
+        if self.opts.word {
+            return;
+        }
+        self.print_prefix();
+        let _ = writeln!(self.output);
+        self.at_column_zero = true;
+    }
+
+    /// detex.l:478 - with -w each word is printed on a line of its own.
+    /// Not in detex.l: with count_only it is counted instead, the rest of
+    /// the output being discarded.
+    fn word(&mut self, word: &str) {
+        if !self.opts.count_only {
+            let _ = writeln!(self.output, "{}", word);
+        } else if !self.output.is_muted() {
+            self.word_count += 1;
+            self.unique_words.insert(word.to_lowercase());
+        }
+    }
+
     /// detex.l:106 - outputs space unless -w flag
     /// Corresponds to: #define SPACE if (!fWord) putchar(' ')
     fn space(&mut self) {
@@ -253,17 +608,43 @@ impl<W: Write> Detex<W> {
     fn noun(&mut self) {
         if self.opts.space && !self.opts.word && !self.opts.replace {
             let _ = write!(self.output, " ");
+        } else if self.opts.replace && self.opts.replace_grammar {
+            self.math_shape = Some(MathShape::default());
         } else if self.opts.replace {
-            let _ = write!(self.output, "noun");
+            let word = self
+                .opts
+                .replace_with
+                .as_deref()
+                .unwrap_or(&self.opts.noun_text);
+            let _ = write!(self.output, "{}", word);
         }
     }
 
     /// detex.l:105 - outputs " verbs noun" for verb symbols in math (if -r)
     /// Corresponds to: #define VERBNOUN if (fReplace) printf(" verbs noun");
+    /// A custom --replace-with word stands for the whole formula, so no
+    /// verb is added.
     fn verb_noun(&mut self) {
-        if self.opts.replace {
-            let _ = write!(self.output, " verbs noun");
+        if let Some(shape) = &mut self.math_shape {
+            shape.relations += 1;
+        } else if self.opts.replace && self.opts.replace_with.is_none() {
+            let _ = write!(self.output, "{}", self.opts.verb_noun_text);
+        }
+    }
+
+    /// Not in detex.l: with replace_grammar, write the words standing for
+    /// the math that has just closed
+    fn end_math(&mut self) {
+        if let Some(shape) = self.math_shape.take() {
+            let _ = write!(self.output, "{}", shape.replacement());
         }
+        self.end_math_identifier();
+    }
+
+    /// Forget identifiers kept with keep_math_identifiers as math ends
+    fn end_math_identifier(&mut self) {
+        self.in_math_identifier = false;
+        self.math_identifier_ended = false;
     }
 
     /// detex.l:757-763 Ignore() - outputs space if -s flag, otherwise nothing
@@ -308,20 +689,55 @@ impl<W: Write> Detex<W> {
         self.la_begin(State::LaMacro2);
     }
 
+    /// Text written in place of a dropped \cite, if any
+    fn cite_placeholder(&self) -> Option<&str> {
+        if self.opts.is_latex() && !self.opts.cite && !self.opts.word {
+            self.opts.cite_placeholder.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Not in detex.l: with -c in LaTeX mode the key of a \cite is echoed
+    /// rather than killed
+    fn echo_cite_key(&self) -> bool {
+        self.opts.is_latex() && self.opts.cite && !self.opts.word
+    }
+
     /// detex.l:788-800 BeginEnv() - check if env should be ignored
-    /// Returns true if the environment is in the ignore list.
+    /// Returns true if the environment is in the ignore list. A starred
+    /// environment is ignored if its unstarred name is listed, and must
+    /// then be closed by the starred name.
     fn begin_env(&mut self, env: &str) -> bool {
         if !self.opts.is_latex() {
             return false;
         }
-        if self.opts.env_ignore.iter().any(|e| e == env) {
+        let base = env.strip_suffix('*').unwrap_or(env);
+        let kind = self.env_kind(env);
+        if self
+            .opts
+            .env_ignore
+            .iter()
+            .any(|e| e == env || e == base || *e == kind)
+        {
             self.current_ignored_env = env.to_string();
+            self.ignored_env_depth = 0;
             true
         } else {
             false
         }
     }
 
+    /// The built-in environment an environment behaves like: its target in
+    /// env_aliases, or else itself
+    fn env_kind(&self, env: &str) -> String {
+        self.opts
+            .env_aliases
+            .get(env)
+            .cloned()
+            .unwrap_or_else(|| env.to_string())
+    }
+
     /// detex.l:806-813 EndEnv() - check if env matches current ignored env
     fn end_env(&mut self, env: &str) -> bool {
         if !self.opts.is_latex() {
@@ -330,8 +746,29 @@ impl<W: Write> Detex<W> {
         env == self.current_ignored_env
     }
 
+    /// detex.l:1073-1081 Warning() - print a warning and record it
+    fn warn(&mut self, message: String) {
+        if !self.opts.suppress_warnings {
+            eprintln!("detex: warning: {}", message);
+        }
+        self.warnings.push(message);
+    }
+
+    /// With strict_latex, warn about a plain TeX construct in the input
+    fn warn_plain_tex(&mut self, construct: &str) {
+        if self.opts.strict_latex {
+            let message = format!(
+                "{}:{}: plain TeX {} in LaTeX",
+                self.current_filename(),
+                self.current_line(),
+                construct
+            );
+            self.warn(message);
+        }
+    }
+
     /// detex.l:847-868 IncludeFile() - include file if in includeonly list
-    fn include_file(&mut self, filename: &str) -> Result<(), String> {
+    fn include_file(&mut self, filename: &str) -> Result<(), DetexError> {
         if self.opts.no_follow {
             return Ok(());
         }
@@ -342,39 +779,59 @@ impl<W: Write> Detex<W> {
     }
 
     /// detex.l:821-840 InputFile() - push current file and open new one
-    fn input_file(&mut self, filename: &str) -> Result<(), String> {
+    fn input_file(&mut self, filename: &str) -> Result<(), DetexError> {
         if self.opts.no_follow {
             return Ok(());
         }
         self.open_file(filename, "\\input")
     }
 
+    /// Read the directory and file arguments of \import and its relatives,
+    /// returning the joined path, taken relative to the current file's
+    /// directory if `relative`
+    fn read_import_path(&mut self, relative: bool) -> Option<String> {
+        self.match_optional_star();
+        let mut args = [String::new(), String::new()];
+        for arg in &mut args {
+            self.skip_whitespace();
+            if !self.try_match("{") {
+                return None;
+            }
+            *arg = self.read_raw_group().trim().to_string();
+        }
+        let [dir, file] = args;
+        let mut path = PathBuf::new();
+        if relative && let Some(parent) = Path::new(self.current_filename()).parent() {
+            path.push(parent);
+        }
+        path.push(dir);
+        path.push(file);
+        Some(path.to_string_lossy().into_owned())
+    }
+
     /// Common implementation for input_file and include_file
-    fn open_file(&mut self, filename: &str, command: &str) -> Result<(), String> {
+    fn open_file(&mut self, filename: &str, command: &str) -> Result<(), DetexError> {
         if self.file_stack.len() >= MAX_FILE_STACK {
-            eprintln!("detex: warning: file stack overflow, ignoring {}", filename);
+            self.warn(DetexError::FileStackOverflow(filename.to_string()).to_string());
             return Ok(());
         }
 
-        match tex_open(filename, &self.opts) {
-            Some((mut file, _path)) => {
-                let mut content = String::new();
-                if let Err(e) = file.read_to_string(&mut content) {
-                    if !self.opts.suppress_warnings {
-                        eprintln!("detex: warning: can't read file {}: {}", filename, e);
-                    }
+        match self.resolver.resolve(filename, &self.opts) {
+            Some((file, path)) => {
+                // Not in detex.l: a file that is already open would be read
+                // again until the file stack overflows
+                let path = Some(canonical_path(&path));
+                if self.file_stack.iter().any(|ctx| ctx.path == path) {
+                    self.warn(DetexError::IncludeCycle(filename.to_string()).to_string());
                     return Ok(());
                 }
-
-                let source = CharSource::new(content);
+                let source = CharSource::for_input(file, &self.opts);
                 let name = filename.to_string();
-                self.file_stack.push(FileContext { source, name });
+                self.file_stack.push(FileContext { source, name, path });
                 Ok(())
             }
             None => {
-                if !self.opts.suppress_warnings {
-                    eprintln!("detex: warning: can't open {} file {}", command, filename);
-                }
+                self.warn(format!("can't open {} file {}", command, filename));
                 Ok(())
             }
         }
@@ -397,8 +854,8 @@ impl<W: Write> Detex<W> {
         self.current_source_mut().and_then(|s| s.next())
     }
 
-    fn peek_char(&self) -> Option<char> {
-        self.current_source().and_then(|s| s.peek())
+    fn peek_char(&mut self) -> Option<char> {
+        self.current_source_mut().and_then(|s| s.peek())
     }
 
     fn unget_char(&mut self, c: char) {
@@ -436,6 +893,51 @@ impl<W: Write> Detex<W> {
         name
     }
 
+    /// Read an environment name for \begin or \end. Unlike detex.l's {W},
+    /// this accepts digits and '*' as well, for names such as align* and
+    /// subequations2.
+    fn read_env_name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(c) = self.peek_char() {
+            if c.is_ascii_alphanumeric() || c == '*' || c == '@' {
+                name.push(c);
+                self.next_char();
+            } else {
+                break;
+            }
+        }
+        name
+    }
+
+    /// The environment name in a `{env}` group at the current position,
+    /// without consuming it
+    fn peek_env_name(&mut self) -> String {
+        let lookahead = match self.current_source_mut() {
+            Some(source) => source.peek_ahead(64),
+            None => return String::new(),
+        };
+        let rest = lookahead.trim_start();
+        match rest.strip_prefix('{') {
+            Some(group) => group
+                .trim_start()
+                .chars()
+                .take_while(|&c| c.is_ascii_alphanumeric() || c == '*' || c == '@')
+                .collect(),
+            None => String::new(),
+        }
+    }
+
+    /// Consume an accent command following a backslash, if there is one,
+    /// and return the accented text.
+    fn take_accent(&mut self) -> Option<String> {
+        let lookahead = self.current_source_mut()?.peek_ahead(MAX_ACCENT_LEN);
+        let (len, accented) = parse_accent(&lookahead)?;
+        for _ in 0..len {
+            self.next_char();
+        }
+        Some(accented)
+    }
+
     fn try_match(&mut self, s: &str) -> bool {
         let chars: Vec<char> = s.chars().collect();
         let mut matched = Vec::new();
@@ -461,10 +963,12 @@ impl<W: Write> Detex<W> {
         true
     }
 
-    fn match_optional_star(&mut self) {
-        if self.peek_char() == Some('*') {
+    fn match_optional_star(&mut self) -> bool {
+        let starred = self.peek_char() == Some('*');
+        if starred {
             self.next_char();
         }
+        starred
     }
 
     fn skip_glue(&mut self) {
@@ -535,6 +1039,151 @@ impl<W: Write> Detex<W> {
         }
     }
 
+    /// Read the rest of a brace group whose '{' was already consumed,
+    /// returning its contents without interpreting commands. Line breaks
+    /// inside are counted but dropped from the result.
+    fn read_raw_group(&mut self) -> String {
+        let mut text = String::new();
+        let mut depth = 1;
+        while let Some(c) = self.next_char() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                '\n' => {
+                    if let Some(source) = self.current_source_mut() {
+                        source.incr_line();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            text.push(c);
+        }
+        text
+    }
+
+    /// Read a \newcommand definition after the command name and record it
+    /// if it takes no parameters. Parameterized macros are dropped like
+    /// without expand_macros, and \providecommand keeps an existing one.
+    fn define_macro(&mut self, keep_existing: bool) {
+        self.match_optional_star();
+        self.skip_whitespace();
+        let braced = self.try_match("{");
+        self.skip_whitespace();
+        let name = if self.try_match("\\") {
+            self.read_command_name()
+        } else {
+            String::new()
+        };
+        self.skip_whitespace();
+        if braced {
+            self.try_match("}");
+        }
+        self.skip_whitespace();
+        let has_params = self.peek_char() == Some('[');
+        self.skip_optional_bracket_args();
+        self.skip_whitespace();
+        if !self.try_match("{") {
+            return;
+        }
+        let body = self.read_macro_body();
+        if name.is_empty() || has_params || (keep_existing && self.macros.contains_key(&name)) {
+            return;
+        }
+        self.macros.insert(name, body);
+    }
+
+    /// Read a brace-balanced macro body after its '{', dropping comments and
+    /// turning newlines into spaces as TeX does
+    fn read_macro_body(&mut self) -> String {
+        let mut body = String::new();
+        let mut depth = 1;
+        while let Some(c) = self.next_char() {
+            match c {
+                '\\' => {
+                    body.push(c);
+                    if let Some(escaped) = self.next_char() {
+                        body.push(escaped);
+                    }
+                    continue;
+                }
+                '%' => {
+                    while let Some(c) = self.next_char() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                    if let Some(source) = self.current_source_mut() {
+                        source.incr_line();
+                    }
+                    continue;
+                }
+                '\n' => {
+                    if let Some(source) = self.current_source_mut() {
+                        source.incr_line();
+                    }
+                    body.push(' ');
+                    continue;
+                }
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(c);
+        }
+        body
+    }
+
+    /// Push the definition of a recorded macro back onto the input in place
+    /// of its use. Returns false if `cmd` isn't a recorded macro.
+    fn expand_macro(&mut self, cmd: &str) -> bool {
+        let Some(body) = self.macros.get(cmd).cloned() else {
+            return false;
+        };
+        if self.macro_expansions >= MAX_MACRO_EXPANSIONS {
+            if self.macro_expansions == MAX_MACRO_EXPANSIONS {
+                self.warn(format!(
+                    "too many macro expansions, no longer expanding \\{}",
+                    cmd
+                ));
+                self.macro_expansions += 1;
+            }
+            return false;
+        }
+        self.macro_expansions += 1;
+        // Unlike TeX, the space after the use is kept so words don't run
+        // together. A body ending in a control word gets a space when
+        // letters follow, so the two aren't read as one command.
+        let letters = body.trim_end_matches(|c: char| c.is_ascii_alphabetic() || c == '@');
+        if letters.len() < body.len()
+            && letters.ends_with('\\')
+            && self.peek_char().is_some_and(|c| c.is_ascii_alphabetic())
+        {
+            self.unget_char(' ');
+        }
+        for c in body.chars().rev() {
+            self.unget_char(c);
+        }
+        true
+    }
+
+    /// Skip any number of [...] arguments
+    fn skip_optional_bracket_args(&mut self) {
+        while self.peek_char() == Some('[') {
+            self.skip_optional_bracket_arg();
+        }
+    }
+
     fn skip_optional_bracket_arg(&mut self) {
         if self.peek_char() == Some('[') {
             self.next_char();
@@ -573,7 +1222,7 @@ impl<W: Write> Detex<W> {
     /// Process Normal state - the main text processing state.
     /// This handles most LaTeX constructs in the document body.
     /// See detex.l lines 212-485 for the Normal state rules.
-    fn process_normal(&mut self) -> Result<(), String> {
+    fn process_normal(&mut self) -> Result<(), DetexError> {
         let c = match self.next_char() {
             Some(c) => c,
             None => return Ok(()),
@@ -603,6 +1252,7 @@ impl<W: Write> Detex<W> {
             '$' => {
                 if self.peek_char() == Some('$') {
                     self.next_char();
+                    self.warn_plain_tex("$$");
                     self.state = State::Display;
                     self.noun();
                 } else {
@@ -657,14 +1307,40 @@ impl<W: Write> Detex<W> {
             // detex.l:470-476 - <Normal>"}" - decrement brace level, check footnote
             '}' => {
                 self.current_braces_level = self.current_braces_level.saturating_sub(1);
+                if let Some((level, state)) = self.prose_end
+                    && level == self.current_braces_level
+                {
+                    self.prose_end = None;
+                    self.state = state;
+                    return Ok(());
+                }
+                if self.caption_level == Some(self.current_braces_level) {
+                    self.caption_level = None;
+                    self.line_break();
+                    self.line_ended = true;
+                }
                 if self.current_braces_level as i32 == self.footnote_level {
                     let _ = write!(self.output, ")");
                     self.footnote_level = -100;
                 }
             }
 
+            // End of an \item[label], see process_backslash
+            ']' if self.item_label_level == Some(self.current_braces_level) => {
+                self.item_label_level = None;
+                if !self.opts.word {
+                    self.echo(':');
+                }
+            }
+
             // detex.l:460 - <Normal>~ - non-breaking space -> space
-            '~' => self.space(),
+            // Not in detex.l: opts.tilde_output can keep the tie
+            '~' => match self.opts.tilde_output {
+                TildeOutput::Space => self.space(),
+                TildeOutput::Nbsp if !self.opts.word => self.echo('\u{a0}'),
+                TildeOutput::Literal if !self.opts.word => self.echo('~'),
+                _ => {}
+            },
 
             // detex.l:458 - <Normal>[\\|] - ignore pipe, output space if -s
             '|' => self.ignore(),
@@ -678,7 +1354,8 @@ impl<W: Write> Detex<W> {
                 }
             }
 
-            // detex.l:461 - <Normal>-{2,3} - em/en dash -> single dash
+            // detex.l:461 - <Normal>-{2,3} - em/en dash -> single dash.
+            // Not in detex.l: with unicode_punct they become – and —.
             '-' => {
                 let mut dashes = 1;
                 while self.peek_char() == Some('-') && dashes < 3 {
@@ -686,19 +1363,25 @@ impl<W: Write> Detex<W> {
                     dashes += 1;
                 }
                 if !self.opts.word {
-                    let _ = write!(self.output, "-");
+                    let dash = match dashes {
+                        2 if self.opts.unicode_punct => "\u{2013}",
+                        3 if self.opts.unicode_punct => "\u{2014}",
+                        _ => "-",
+                    };
+                    let _ = write!(self.output, "{}", dash);
                 }
             }
 
             // detex.l:462-463 - <Normal>`` -> " and <Normal>` -> '
+            // Not in detex.l: with unicode_punct, quotes become curly ones
             '`' => {
                 if self.peek_char() == Some('`') {
                     self.next_char();
                     if !self.opts.word {
-                        let _ = write!(self.output, "\"");
+                        self.write_quote("\"", "\u{201c}");
                     }
                 } else if !self.opts.word {
-                    let _ = write!(self.output, "'");
+                    self.write_quote("'", "\u{2018}");
                 }
             }
 
@@ -707,10 +1390,17 @@ impl<W: Write> Detex<W> {
                 if self.peek_char() == Some('\'') {
                     self.next_char();
                     if !self.opts.word {
-                        let _ = write!(self.output, "\"");
+                        self.write_quote("\"", "\u{201d}");
                     }
                 } else if !self.opts.word {
-                    self.echo(c);
+                    // An apostrophe inside a word (don't) stays ASCII
+                    if self.opts.unicode_punct
+                        && !self.peek_char().is_some_and(|c| c.is_alphanumeric())
+                    {
+                        self.echo('\u{2019}');
+                    } else {
+                        self.echo(c);
+                    }
                 }
             }
 
@@ -719,7 +1409,7 @@ impl<W: Write> Detex<W> {
                 if self.peek_char() == Some(',') {
                     self.next_char();
                     if !self.opts.word {
-                        let _ = write!(self.output, "\"");
+                        self.write_quote("\"", "\u{201e}");
                     }
                 } else if !self.opts.word {
                     self.echo(c);
@@ -728,11 +1418,21 @@ impl<W: Write> Detex<W> {
 
             // detex.l:484 - <Normal>"\n" - newline
             '\n' => {
-                if !self.opts.word {
+                if self.line_ended && self.output.line_blank() {
+                    if let Some(source) = self.current_source_mut() {
+                        source.incr_line();
+                    }
+                    self.at_column_zero = true;
+                } else if !self.opts.word {
                     self.newline();
                 }
+                self.line_ended = false;
             }
 
+            // Not in detex.l: cells of a table that is not ignored are
+            // separated by a space rather than the '&'
+            '&' if self.table_depth > 0 => self.space(),
+
             // detex.l:485 - <Normal>("\t")+ - tabs
             // In flex, single tab is matched by '.' rule (line 483) which calls ECHO (with prefix)
             // Multiple consecutive tabs are matched by ("\t")+ (line 485) which calls putchar('\t')
@@ -755,17 +1455,28 @@ impl<W: Write> Detex<W> {
             }
 
             // detex.l:477-481 - <Normal>{W}[']*{W} - words with apostrophes
-            _ if c.is_ascii_alphabetic() => {
+            // Unlike detex.l's [a-zA-Z], any Unicode letter is a word character.
+            _ if c.is_alphabetic() => {
                 let mut word = String::new();
                 word.push(c);
 
                 while let Some(ch) = self.peek_char() {
-                    if ch.is_ascii_alphabetic() {
+                    if ch.is_alphabetic() || is_combining_mark(ch) {
                         word.push(self.next_char().unwrap());
+                    } else if ch == '\\' {
+                        // An accent command continues the word: M\"uller
+                        self.next_char();
+                        match self.take_accent() {
+                            Some(accented) => word.push_str(&accented),
+                            None => {
+                                self.unget_char('\\');
+                                break;
+                            }
+                        }
                     } else if ch == '\'' {
                         self.next_char();
                         if let Some(next) = self.peek_char() {
-                            if next.is_ascii_alphabetic() {
+                            if next.is_alphabetic() {
                                 word.push('\'');
                             } else {
                                 self.unget_char('\'');
@@ -780,8 +1491,9 @@ impl<W: Write> Detex<W> {
                     }
                 }
 
+                self.record_word();
                 if self.opts.word {
-                    let _ = writeln!(self.output, "{}", word);
+                    self.word(&word);
                 } else {
                     self.echo_str(&word);
                 }
@@ -807,10 +1519,16 @@ impl<W: Write> Detex<W> {
             _ => {
                 if !self.opts.word {
                     // detex.l:327 - <Normal>" "?"\\cite" - kill space before \cite
-                    // Check for space before \cite and don't output it
-                    if c == ' ' && self.peek_char() == Some('\\') {
+                    // Check for space before \cite and don't output it,
+                    // unless a placeholder or, with -c, the key takes the
+                    // citation's place
+                    if c == ' '
+                        && self.peek_char() == Some('\\')
+                        && self.cite_placeholder().is_none()
+                        && !self.echo_cite_key()
+                    {
                         // Peek ahead to see if this is \cite
-                        if let Some(src) = self.current_source() {
+                        if let Some(src) = self.current_source_mut() {
                             let lookahead = src.peek_ahead(6); // Look at next 6 chars: \cite + potential next char
                             if lookahead.len() >= 5 && lookahead[0..5] == *"\\cite" {
                                 // Check that "cite" is not followed by more letters (i.e., it's not \citation)
@@ -849,9 +1567,22 @@ impl<W: Write> Detex<W> {
     /// - detex.l:403-431: File inclusion (\include, \includeonly, \subfile, \input)
     /// - detex.l:434-439: Special characters and ligatures (\slash, \aa, \O, \linebreak, etc.)
     /// - detex.l:441-444: Generic escape sequences (\\, \ , \%, \., etc.)
-    fn process_backslash(&mut self) -> Result<(), String> {
+    fn process_backslash(&mut self) -> Result<(), DetexError> {
+        // Not in detex.l: accent commands (\"o, \'{e}, \c{c}) become the
+        // accented letter, pushed back so that it starts a word.
+        if let Some(accented) = self.take_accent() {
+            for c in accented.chars().rev() {
+                self.unget_char(c);
+            }
+            return Ok(());
+        }
+
         let cmd = self.read_command_name();
 
+        if self.expand_macro(&cmd) {
+            return Ok(());
+        }
+
         if cmd.is_empty() {
             // Non-alphabetic command: \(, \[, \\, \ , \%, \$, etc.
             match self.next_char() {
@@ -870,6 +1601,15 @@ impl<W: Write> Detex<W> {
                     self.match_optional_star();
                     self.skip_optional_bracket_arg();
                     self.newline();
+                    // Not in detex.l: a row break at the end of a source
+                    // line in a table doesn't also leave a blank line, and
+                    // the next row doesn't start with a space
+                    if self.table_depth > 0 {
+                        while matches!(self.peek_char(), Some(' ' | '\t')) {
+                            self.next_char();
+                        }
+                        self.line_ended = true;
+                    }
                 }
                 // detex.l:442 - <Normal>"\\ " - explicit space
                 Some(' ') => self.space(),
@@ -893,6 +1633,10 @@ impl<W: Write> Detex<W> {
             return Ok(());
         }
 
+        if self.metadata.is_some() {
+            self.record_metadata(&cmd);
+        }
+
         match cmd.as_str() {
             // detex.l:214-258 - \begin{...} handling
             // Line 216: <Normal>"\\begin" {LaBEGIN LaBegin; IGNORE;}
@@ -910,12 +1654,17 @@ impl<W: Write> Detex<W> {
                 self.skip_whitespace();
                 if self.try_match("{") {
                     self.skip_whitespace();
-                    let env = self.read_command_name();
+                    let env = self.read_env_name();
                     self.skip_whitespace();
                     self.try_match("}");
 
+                    // Not in detex.l: an environment named in env_aliases
+                    // behaves like its built-in target, but is still closed
+                    // by its own name
+                    let kind = self.env_kind(&env);
+
                     // detex.l:214 - \begin{document}
-                    if env == "document" {
+                    if kind == "document" {
                         self.set_latex();
                         // detex.l:214 - pattern is: "\\begin"{S}"{"{S}"document"{S}"}""\n"*
                         // The "\n"* part consumes optional newlines
@@ -929,23 +1678,44 @@ impl<W: Write> Detex<W> {
                         }
                         // detex.l:214 has IGNORE but document is special (LATEX; IGNORE)
                         // detex.l:218-223 - \begin{verbatim}
-                    } else if env == "verbatim" {
-                        if self.begin_env("verbatim") {
+                    } else if kind == "verbatim" {
+                        if self.begin_env(&env) {
                             self.state = State::LaEnv;
                         } else {
+                            self.verbatim_env = env;
                             self.state = State::LaVerbatim;
                         }
                         self.ignore(); // detex.l:222 IGNORE
+                    // Not in detex.l: the comment package's environment is
+                    // dropped whatever the ignore list says
+                    } else if kind == "comment" {
+                        self.verbatim_env = env;
+                        self.state = State::LaComment;
+                        self.ignore();
+                    // Not in detex.l: code listings are echoed like verbatim,
+                    // skipping their [options] and minted's {language}
+                    } else if matches!(kind.as_str(), "lstlisting" | "minted" | "Verbatim") {
+                        if self.begin_env(&env) {
+                            self.state = State::LaEnv;
+                        } else {
+                            self.skip_optional_bracket_arg();
+                            if kind == "minted" {
+                                self.skip_brace_arg();
+                            }
+                            self.verbatim_env = env;
+                            self.state = State::LaVerbatim;
+                        }
+                        self.ignore();
                     // detex.l:229-235 - \begin{minipage}
-                    } else if env == "minipage" {
+                    } else if kind == "minipage" {
                         self.kill_args(1); // detex.l:229 KILLARGS(1)
-                        if self.begin_env("minipage") {
+                        if self.begin_env(&env) {
                             self.state = State::LaEnv;
                         }
                         // State is either LaEnv or LaMacro (from kill_args)
                         self.ignore(); // detex.l:234 IGNORE
                     // detex.l:237-251 - \begin{table}[pos] or \begin{figure}[pos]
-                    } else if env == "table" || env == "figure" {
+                    } else if matches!(kind.as_str(), "table" | "figure" | "table*" | "figure*") {
                         self.skip_whitespace();
                         self.skip_optional_bracket_arg();
                         if self.begin_env(&env) {
@@ -954,8 +1724,21 @@ impl<W: Write> Detex<W> {
                         self.ignore(); // detex.l:242,250 IGNORE
                     // detex.l:253-258 - \begin{other_env}
                     } else {
+                        // Not in detex.l: the column specification of a
+                        // table, and the width of tabular* and tabularx,
+                        // is not text
+                        if is_table_env(&kind) {
+                            if matches!(kind.as_str(), "tabular*" | "tabularx") {
+                                self.skip_brace_arg();
+                            }
+                            self.skip_whitespace();
+                            self.skip_optional_bracket_arg();
+                            self.skip_brace_arg();
+                        }
                         if self.begin_env(&env) {
                             self.state = State::LaEnv;
+                        } else if is_table_env(&kind) {
+                            self.table_depth += 1;
                         }
                         self.ignore(); // detex.l:257 IGNORE (outside the if/else)
                     }
@@ -964,6 +1747,10 @@ impl<W: Write> Detex<W> {
 
             // detex.l:331 - <Normal>"\\end" {KILLARGS(1); IGNORE;}
             "end" => {
+                let env = self.peek_env_name();
+                if is_table_env(&self.env_kind(&env)) {
+                    self.table_depth = self.table_depth.saturating_sub(1);
+                }
                 self.kill_args(1);
                 self.ignore();
             }
@@ -975,6 +1762,28 @@ impl<W: Write> Detex<W> {
             "kern" | "vskip" | "hskip" => {
                 self.skip_glue();
             }
+            // Not in detex.l: line breaking hints are dropped without the
+            // IGNORE of Control, along with the spaces after them as in TeX,
+            // and \penalty with its number
+            "allowbreak" | "nobreak" => {
+                while matches!(self.peek_char(), Some(' ' | '\t')) {
+                    self.next_char();
+                }
+            }
+            "penalty" => {
+                self.skip_whitespace();
+                if matches!(self.peek_char(), Some('+' | '-')) {
+                    self.next_char();
+                }
+                if self.try_match("\\") {
+                    let _ = self.read_command_name();
+                } else {
+                    while self.peek_char().is_some_and(|c| c.is_ascii_digit()) {
+                        self.next_char();
+                    }
+                }
+                self.try_match(" ");
+            }
             // <Normal>"\\vspace"{Z}{S}"{"{VG}"}"  ;
             // <Normal>"\\hspace"{Z}{S}"{"{HG}"}"  ;
             "vspace" | "hspace" => {
@@ -1006,6 +1815,32 @@ impl<W: Write> Detex<W> {
                 // No IGNORE
             }
 
+            // Not in detex.l: rules in a table that is not ignored are
+            // dropped with their arguments, and so is the end of their line
+            "hline" | "cline" | "toprule" | "midrule" | "bottomrule" | "cmidrule"
+            | "addlinespace"
+                if self.opts.is_latex() && self.table_depth > 0 =>
+            {
+                self.skip_optional_bracket_arg();
+                if cmd == "cmidrule" && self.try_match("(") {
+                    while !matches!(self.next_char(), Some(')') | None) {}
+                }
+                if matches!(cmd.as_str(), "cline" | "cmidrule") {
+                    self.skip_brace_arg();
+                }
+                while matches!(self.peek_char(), Some(' ' | '\t')) {
+                    self.next_char();
+                }
+                self.line_ended = true;
+            }
+
+            // Not in detex.l: cells of a table that is not ignored keep
+            // their text but not the column count and spec, or the row
+            // count and width
+            "multicolumn" | "multirow" if self.opts.is_latex() && self.table_depth > 0 => {
+                self.strip_args(3);
+            }
+
             // detex.l:295 - <Normal>"\\resizebox"{Z} {KILLARGS(2);}
             "resizebox" => {
                 self.match_optional_star();
@@ -1038,9 +1873,7 @@ impl<W: Write> Detex<W> {
             // detex.l:298 - <Normal>"\\includegraphics"[^{]* {LaBEGIN LaPicture;}
             "includegraphics" => {
                 // Skip any [...] options before the {filename}
-                while self.peek_char() == Some('[') {
-                    self.skip_optional_bracket_arg();
-                }
+                self.skip_optional_bracket_args();
                 self.la_begin(State::LaPicture);
             }
 
@@ -1059,10 +1892,30 @@ impl<W: Write> Detex<W> {
 
             // detex.l:327 - <Normal>" "?"\\cite" {KILLARGS(1);}
             // Note: NO IGNORE! The space before is handled in process_normal
+            // Not in detex.l: optional notes (\cite[see][p.~3]{key}) are
+            // dropped, and with -c the key is echoed as it is for \ref
+            // Not in detex.l: opts.cite_placeholder is written in place of
+            // the dropped citation
             "cite" => {
-                self.kill_args(1);
-            }
-
+                if self.opts.is_latex() {
+                    self.skip_optional_bracket_args();
+                }
+                if let Some(placeholder) = self.cite_placeholder() {
+                    let placeholder = placeholder.to_string();
+                    self.echo_str(&placeholder);
+                }
+                if self.echo_cite_key() {
+                    self.skip_whitespace();
+                    if self.try_match("{") {
+                        let key = self.read_raw_group();
+                        self.print_prefix();
+                        let _ = write!(self.output, "{}", key);
+                    }
+                } else {
+                    self.kill_args(1);
+                }
+            }
+
             // detex.l:332-333 - hypersetup, index (no IGNORE)
             "hypersetup" | "index" => {
                 self.kill_args(1);
@@ -1077,11 +1930,58 @@ impl<W: Write> Detex<W> {
 
             // detex.l:336-337,339 - CITE macro (conditional KILLARGS) + IGNORE
             // #define CITE(x) if (fLatex && !fCite) KILLARGS(x)
-            "nameref" | "pageref" | "ref" => {
+            // Not in detex.l: optional [...] arguments are dropped so that -c
+            // echoes only the key. cleveref's \cref, \Cref, \cpageref (and
+            // starred forms), hyperref's \autoref and amsmath's \eqref are
+            // treated the same way.
+            "nameref" | "pageref" | "ref" | "cref" | "Cref" | "cpageref" | "Cpageref"
+            | "autoref" | "eqref" => {
+                self.match_optional_star();
+                if self.opts.is_latex() {
+                    self.skip_optional_bracket_args();
+                }
                 if self.opts.is_latex() && !self.opts.cite {
                     self.kill_args(1);
                 }
                 self.ignore();
+                // Not in detex.l: with -c the key is echoed as written, so
+                // "--" and "~" in it aren't turned into "-" and a space
+                if self.opts.is_latex() && self.opts.cite && !self.opts.word {
+                    self.skip_whitespace();
+                    if self.try_match("{") {
+                        let key = self.read_raw_group();
+                        self.print_prefix();
+                        let _ = write!(self.output, "{}", key);
+                    }
+                }
+            }
+            // Not in detex.l: cleveref and varioref ranges take two keys,
+            // echoed as "first,last" with -c
+            "crefrange" | "Crefrange" | "cpagerefrange" | "Cpagerefrange" | "vrefrange"
+            | "vpagerefrange" => {
+                self.match_optional_star();
+                if !self.opts.is_latex() {
+                    self.state = State::Control;
+                } else if !self.opts.cite {
+                    self.kill_args(2);
+                } else if !self.opts.word {
+                    self.skip_whitespace();
+                    let first = if self.try_match("{") {
+                        self.read_raw_group()
+                    } else {
+                        String::new()
+                    };
+                    self.skip_whitespace();
+                    let last = if self.try_match("{") {
+                        self.read_raw_group()
+                    } else {
+                        String::new()
+                    };
+                    self.print_prefix();
+                    let _ = write!(self.output, "{},{}", first, last);
+                }
+                // With -c and -w the keys are read as words, as for \ref
+                self.ignore();
             }
 
             // detex.l:338 - <Normal>"\\pagestyle" {KILLARGS(1); IGNORE;}
@@ -1140,44 +2040,109 @@ impl<W: Write> Detex<W> {
                 self.ignore();
             }
 
+            // Not in detex.l: the import package's commands name a directory
+            // and a file in it, with \sub... forms relative to this file
+            "import" | "subimport" | "includefrom" | "subincludefrom" if self.opts.is_latex() => {
+                if let Some(path) = self.read_import_path(cmd.starts_with("sub")) {
+                    if cmd.ends_with("import") {
+                        self.input_file(&path)?;
+                    } else {
+                        self.include_file(&path)?;
+                    }
+                }
+            }
+
             // detex.l:426 - <Normal>"\\input" {BEGIN Input; IGNORE;}
             "input" => {
+                let bare = self.current_source_mut().is_some_and(|source| {
+                    let next = source.peek_ahead(64);
+                    let next = next.trim_start_matches([' ', '\t']);
+                    !next.is_empty() && !next.starts_with('{')
+                });
+                if bare {
+                    self.warn_plain_tex("\\input without braces");
+                }
                 self.state = State::Input;
                 self.ignore();
             }
 
             // detex.l:347-351 - <Normal>"\\footnote"(\[([^\]])+\])?"{"
+            // Not in detex.l: a footnote in a caption is dropped, so the
+            // caption stays one line of running text
             "footnote" => {
                 self.skip_optional_bracket_arg();
-                if self.try_match("{") {
+                if self.caption_level.is_some() {
+                    self.skip_brace_arg();
+                } else if self.try_match("{") {
                     let _ = write!(self.output, "(");
                     self.footnote_level = self.current_braces_level as i32;
                     self.current_braces_level += 1;
                 }
             }
 
+            // Not in detex.l: caption text is kept on a line of its own,
+            // without the short caption or \captionof's float type
+            "caption" | "captionof" if self.opts.is_latex() => {
+                if cmd == "captionof" {
+                    self.skip_brace_arg();
+                } else {
+                    self.match_optional_star();
+                }
+                self.skip_whitespace();
+                self.skip_optional_bracket_arg();
+                self.skip_whitespace();
+                if self.try_match("{") {
+                    self.caption_level = Some(self.current_braces_level);
+                    self.current_braces_level += 1;
+                }
+            }
+
             // detex.l:352-367 - <Normal>"\\verb"
-            "verb" => {
-                if self.opts.is_latex()
-                    && let Some(delim) = self.next_char()
-                {
+            // Not in detex.l: \verb* shows spaces as opts.visible_space, and
+            // opts.verb_commands are read the same way
+            name if name == "verb" || self.opts.verb_commands.iter().any(|v| v == name) => {
+                if !self.opts.is_latex() {
+                    return Ok(());
+                }
+                let starred = self.match_optional_star();
+                if let Some(delim) = self.next_char() {
+                    // detex.l:356 - a control character can't delimit \verb,
+                    // but a space can, even though LaTeX rejects it
                     if delim < ' ' {
-                        return Err("\\verb not complete before eof".to_string());
+                        return Err(DetexError::UnterminatedVerb {
+                            line: self.current_line(),
+                        });
                     }
-                    while let Some(c) = self.next_char() {
-                        if c == delim {
-                            break;
-                        }
-                        if c == '\n' || c == '\0' {
-                            return Err("\\verb not complete before eof".to_string());
+                    let _ = self.output.set_verbatim(true);
+                    loop {
+                        match self.next_char() {
+                            Some(c) if c == delim => break,
+                            Some('\n') | Some('\0') | None => {
+                                return Err(DetexError::UnterminatedVerb {
+                                    line: self.current_line(),
+                                });
+                            }
+                            Some(' ') if starred => {
+                                let _ = write!(self.output, "{}", self.opts.visible_space);
+                            }
+                            Some(c) => {
+                                let _ = write!(self.output, "{}", c);
+                            }
                         }
-                        let _ = write!(self.output, "{}", c);
                     }
+                    let _ = self.output.set_verbatim(false);
                 }
             }
 
+            // Not in detex.l: with expand_macros, definitions are recorded
+            // instead of killed
+            "newcommand" | "renewcommand" | "providecommand" if self.opts.expand_macros => {
+                self.set_latex();
+                self.define_macro(cmd == "providecommand");
+            }
             // detex.l:369-371 - newcommand, renewcommand, newenvironment
-            "newcommand" | "renewcommand" => {
+            // (\providecommand is not in detex.l)
+            "newcommand" | "renewcommand" | "providecommand" => {
                 self.set_latex();
                 self.kill_args(2);
             }
@@ -1185,9 +2150,45 @@ impl<W: Write> Detex<W> {
                 self.set_latex();
                 self.kill_args(3);
             }
+            // Not in detex.l: amsmath \DeclareMathOperator{\cmd}{text}
+            "DeclareMathOperator" => {
+                self.match_optional_star();
+                self.kill_args(2);
+            }
+            // Not in detex.l: \ensuremath{...} in text is inline math, so
+            // its argument is pushed back between dollars. In math it is
+            // dropped by math_command and its argument stays math.
+            "ensuremath" if self.opts.is_latex() => {
+                self.skip_whitespace();
+                if self.try_match("{") {
+                    let body = self.read_raw_group();
+                    self.unget_char('$');
+                    for c in body.chars().rev() {
+                        self.unget_char(c);
+                    }
+                    self.unget_char('$');
+                }
+            }
+            // Not in detex.l: mhchem \ce{H2O} and chemmacros \ch{H2O} are
+            // formulas, replaced like inline math
+            "ce" | "ch" => {
+                self.noun();
+                self.end_math();
+                self.kill_args(1);
+            }
 
             // detex.l:373 - <Normal>"\\def" {BEGIN Define; IGNORE;}
+            // Not in detex.l: with skip_def_bodies the macro name is read
+            // here, so a brace in it (\def\{...) doesn't end the parameter
+            // text, and process_define drops the body.
             "def" => {
+                self.warn_plain_tex("\\def");
+                if self.opts.skip_def_bodies
+                    && self.try_match("\\")
+                    && self.read_command_name().is_empty()
+                {
+                    self.next_char();
+                }
                 self.state = State::Define;
                 self.ignore();
             }
@@ -1212,6 +2213,39 @@ impl<W: Write> Detex<W> {
                 }
             }
 
+            // Not in detex.l: \par ends the line and leaves a blank one, so
+            // paragraphs stay separate (nothing is written with -w)
+            "par" => {
+                if !self.output.line_blank() {
+                    self.line_break();
+                }
+                self.line_break();
+                while matches!(self.peek_char(), Some(' ') | Some('\t')) {
+                    self.next_char();
+                }
+            }
+
+            // Not in detex.l: ellipses become "..." (or "…" with
+            // unicode_punct). Following spaces or an empty group are
+            // consumed, but unlike the ligatures not a newline or '}', which
+            // would upset line and brace counts.
+            "ldots" | "dots" | "cdots" | "textellipsis" => {
+                if !self.opts.word {
+                    let ellipsis = if self.opts.unicode_punct {
+                        "\u{2026}"
+                    } else {
+                        "..."
+                    };
+                    self.print_prefix();
+                    let _ = write!(self.output, "{}", ellipsis);
+                }
+                if !self.try_match("{}") {
+                    while matches!(self.peek_char(), Some(' ') | Some('\t')) {
+                        self.next_char();
+                    }
+                }
+            }
+
             // detex.l:438 - \\[OoijLl][ \t]*[ \t\n}] - ligatures (1 char)
             "O" | "o" | "i" | "j" | "L" | "l" => {
                 if !self.opts.word {
@@ -1225,6 +2259,66 @@ impl<W: Write> Detex<W> {
                 }
             }
 
+            // Not in detex.l: start each \item on its own line. An optional
+            // [label] is kept as text, followed by ':'. Otherwise this is
+            // handled like an unknown command, as detex.l does.
+            "item" => {
+                if !self.output.line_blank() {
+                    self.line_break();
+                }
+                if self.peek_char() == Some('[') {
+                    self.next_char();
+                    self.item_label_level = Some(self.current_braces_level);
+                    self.ignore();
+                } else {
+                    self.state = State::Control;
+                    self.ignore();
+                }
+            }
+
+            // hyperref \href{url}{text}: drop the URL, keep the text. Not in
+            // detex.l. The text is processed as Normal so its brace is counted.
+            "href" => {
+                self.skip_brace_arg();
+                self.skip_whitespace();
+                if self.try_match("{") {
+                    self.current_braces_level += 1;
+                }
+            }
+
+            // \url{...} and \nolinkurl{...}: echo the argument verbatim, like
+            // \verb. Not in detex.l.
+            "url" | "nolinkurl" => {
+                self.skip_whitespace();
+                if self.try_match("{") {
+                    let url = self.read_raw_group();
+                    self.record_word();
+                    if self.opts.word {
+                        self.word(&url);
+                    } else {
+                        self.echo_str(&url);
+                    }
+                } else {
+                    self.state = State::Control;
+                }
+            }
+
+            // Font commands, and polyglossia's language switches for
+            // right-to-left text: drop the command and keep the braced text
+            // as is, without reordering. Not in detex.l, where they go
+            // through Control; the IGNOREs match what Control does for
+            // "\cmd{" so -s output is unchanged.
+            "textbf" | "textit" | "textsl" | "textsc" | "texttt" | "textrm" | "textsf" | "emph"
+            | "textnormal" | "textup" | "textmd" | "textarabic" | "texthebrew" => {
+                self.ignore();
+                if self.try_match("{") {
+                    self.current_braces_level += 1;
+                    self.ignore();
+                } else {
+                    self.state = State::Control;
+                }
+            }
+
             // detex.l:439 - <Normal>"\\linebreak"(\[[0-4]\])? {NEWLINE;}
             "linebreak" => {
                 self.skip_optional_bracket_arg();
@@ -1245,8 +2339,15 @@ impl<W: Write> Detex<W> {
     /// <Define>"{"   BEGIN Normal;
     /// <Define>"\n"  NEWLINE;
     /// <Define>.     ;
-    fn process_define(&mut self) -> Result<(), String> {
+    ///
+    /// With skip_def_bodies (not in detex.l), the brace-balanced body after
+    /// the parameter text is dropped instead of kept as text.
+    fn process_define(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
+            Some('{') if self.opts.skip_def_bodies => {
+                self.read_macro_body();
+                self.state = State::Normal;
+            }
             Some('{') => self.state = State::Normal,
             Some('\n') => self.newline(),
             Some(_) | None => {}
@@ -1259,12 +2360,13 @@ impl<W: Write> Detex<W> {
     /// <Display>"\n"           NEWLINE;
     /// <Display>{VERBSYMBOL}   VERBNOUN;
     /// <Display>.              ;
-    fn process_display(&mut self) -> Result<(), String> {
+    fn process_display(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
             Some('$') => {
                 if self.peek_char() == Some('$') {
                     self.next_char();
                     self.state = State::Normal;
+                    self.end_math();
                 } else {
                     self.check_verb_symbol('$');
                 }
@@ -1282,20 +2384,29 @@ impl<W: Write> Detex<W> {
     /// <Math>"\\$"          ;
     /// <Math>{VERBSYMBOL}   VERBNOUN;
     /// <Math>.              ;
-    fn process_math(&mut self) -> Result<(), String> {
+    fn process_math(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
-            Some('$') => self.state = State::Normal,
+            Some('$') => {
+                self.state = State::Normal;
+                self.end_math();
+            }
             Some('\\') => {
+                self.keep_math_identifier('\\');
                 if self.peek_char() == Some('$') {
                     self.next_char(); // escaped $ in math mode
                 } else {
                     let cmd = self.read_command_name();
-                    if is_verb_symbol(&cmd) {
-                        self.verb_noun();
-                    }
+                    self.math_command(&cmd);
+                }
+            }
+            // No NEWLINE in Math state, but unlike detex.l count the line
+            // so -1 prefixes after multi-line inline math stay correct
+            Some('\n') => {
+                self.keep_math_identifier('\n');
+                if let Some(source) = self.current_source_mut() {
+                    source.incr_line();
                 }
             }
-            Some('\n') => {} // No NEWLINE in Math state
             Some(c) => self.check_verb_symbol(c),
             None => {}
         }
@@ -1308,20 +2419,246 @@ impl<W: Write> Detex<W> {
     /// This includes the character symbols: = > <
     /// And the LaTeX command symbols: \leq \geq \in \subseteq \subset \supset \sim \neq \mapsto
     fn check_verb_symbol(&mut self, c: char) {
+        self.keep_math_identifier(c);
         match c {
             // detex.l:204 - character symbols: = > <
             '=' | '>' | '<' => self.verb_noun(),
+            // Not in detex.l: arithmetic, for replace_grammar
+            '+' | '-' | '*' | '/' => {
+                if let Some(shape) = &mut self.math_shape {
+                    shape.operators += 1;
+                }
+            }
             '\\' => {
                 let cmd = self.read_command_name();
-                // detex.l:204 - command symbols like \leq, \geq, etc.
-                if is_verb_symbol(&cmd) {
-                    self.verb_noun();
-                }
+                self.math_command(&cmd);
             }
             _ => {}
         }
     }
 
+    /// Look ahead, after a \begin, for an abstract environment and return
+    /// its body without consuming anything
+    fn peek_abstract(&mut self) -> Option<String> {
+        let mut n = 256;
+        loop {
+            let ahead = self.current_source_mut()?.peek_ahead(n);
+            let body = ahead.trim_start().strip_prefix("{abstract}")?;
+            if let Some(end) = body.find("\\end{abstract}") {
+                return Some(body[..end].to_string());
+            }
+            if ahead.chars().count() < n {
+                return None;
+            }
+            n *= 4;
+        }
+    }
+
+    /// Look ahead past a star and optional [...] arguments to a brace
+    /// argument, and return its contents without consuming anything
+    fn peek_brace_arg(&mut self) -> Option<String> {
+        let mut n = 256;
+        loop {
+            let ahead = self.current_source_mut()?.peek_ahead(n);
+            match scan_brace_arg(&ahead) {
+                Some(arg) => return arg,
+                // The argument runs past what was read, unless that was all
+                None if ahead.chars().count() < n => return None,
+                None => n *= 4,
+            }
+        }
+    }
+
+    /// Record the title, authors, date, abstract, sections, labels and
+    /// citations for `extract`, looking ahead at the argument of cmd, or
+    /// the body of the environment it begins, so the text output is
+    /// unchanged
+    fn record_metadata(&mut self, cmd: &str) {
+        if cmd == "begin" {
+            if let Some(body) = self.peek_abstract()
+                && let Some(metadata) = self.metadata.as_mut()
+            {
+                metadata.abstract_text = Some(metadata_text(&body));
+            }
+            return;
+        }
+        let depth = match cmd {
+            "part" => Some(0),
+            "chapter" => Some(1),
+            "section" => Some(2),
+            "subsection" => Some(3),
+            "subsubsection" => Some(4),
+            "paragraph" => Some(5),
+            "subparagraph" => Some(6),
+            "title" | "author" | "date" | "label" => None,
+            cmd if is_citation(cmd) => None,
+            _ => return,
+        };
+        let Some(arg) = self.peek_brace_arg() else {
+            return;
+        };
+        let Some(metadata) = self.metadata.as_mut() else {
+            return;
+        };
+        match (cmd, depth) {
+            (_, Some(depth)) => metadata.sections.push((depth, metadata_text(&arg))),
+            ("title", _) => metadata.title = Some(metadata_text(&arg)),
+            ("date", _) => metadata.date = Some(metadata_text(&arg)),
+            ("author", _) => metadata.authors.extend(
+                arg.split("\\and")
+                    .map(|author| metadata_text(&strip_thanks(author)))
+                    .filter(|author| !author.is_empty()),
+            ),
+            ("label", _) => metadata.labels.push(arg.trim().to_string()),
+            _ => metadata.citations.extend(
+                arg.split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(String::from),
+            ),
+        }
+    }
+
+    /// Count a word of the text for `extract`
+    fn record_word(&mut self) {
+        if !self.output.is_muted()
+            && let Some(metadata) = self.metadata.as_mut()
+        {
+            metadata.word_count += 1;
+        }
+    }
+
+    /// With keep_math_identifiers, write the letters of identifiers in
+    /// math, which is at c, with a space between identifiers that aren't
+    /// already separated
+    fn keep_math_identifier(&mut self, c: char) {
+        if !(self.opts.keep_math_text && self.opts.keep_math_identifiers) {
+            return;
+        }
+        if c.is_alphabetic() {
+            self.separate_math_word();
+            self.echo(c);
+            self.in_math_identifier = true;
+        } else if self.in_math_identifier {
+            self.in_math_identifier = false;
+            self.math_identifier_ended = true;
+        }
+    }
+
+    /// Write a space if an identifier kept with keep_math_identifiers ended
+    /// since the output last had one
+    fn separate_math_word(&mut self) {
+        if self.math_identifier_ended && !self.output.line_blank() && !self.output.ends_with_space()
+        {
+            self.space();
+        }
+        self.math_identifier_ended = false;
+    }
+
+    /// Process the argument of \intertext, or of \text, \mbox or
+    /// \operatorname with keep_math_text, as Normal text, returning to the
+    /// current math state at its closing brace
+    fn begin_math_prose(&mut self) {
+        self.match_optional_star();
+        self.skip_whitespace();
+        if self.try_match("{") {
+            self.prose_end = Some((self.current_braces_level, self.state));
+            self.current_braces_level += 1;
+            self.state = State::Normal;
+        }
+    }
+
+    /// With keep_math_text, keep the prose of \text and similar commands,
+    /// and the letters of \mathbb and similar commands or the name given
+    /// for them in math_letter_names, and write math spacing such as \quad
+    /// and \, and row breaks such as those in \substack as a space so the
+    /// words kept don't run together. Returns whether cmd was one of these.
+    fn keep_math_words(&mut self, cmd: &str) -> bool {
+        if !self.opts.keep_math_text {
+            return false;
+        }
+        if cmd.is_empty() && self.try_match("\\") {
+            // A row break at the end of a line is already a break
+            while matches!(self.peek_char(), Some(' ' | '\t')) {
+                self.next_char();
+            }
+            if self.peek_char() != Some('\n') {
+                self.space();
+            }
+            return true;
+        }
+        if cmd.is_empty() && matches!(self.peek_char(), Some(',' | ':' | ';' | ' ')) {
+            self.next_char();
+            self.space();
+            return true;
+        }
+        if is_math_space(cmd) {
+            self.space();
+            return true;
+        }
+        if is_math_text(cmd) {
+            self.begin_kept_math_prose();
+            return true;
+        }
+        if !is_math_alphabet(cmd) {
+            return false;
+        }
+        self.skip_whitespace();
+        if self.try_match("{") {
+            let letters = self.read_raw_group();
+            let written = format!("\\{}{{{}}}", cmd, letters);
+            let name = self
+                .opts
+                .math_letter_names
+                .iter()
+                .find(|(source, _)| *source == written)
+                .map_or(letters, |(_, name)| name.clone());
+            // Push the name back as a group to process it like \text
+            self.unget_char('}');
+            for c in name.chars().rev() {
+                self.unget_char(c);
+            }
+            self.unget_char('{');
+            self.begin_kept_math_prose();
+        }
+        true
+    }
+
+    /// Begin kept prose in math, separated from identifiers kept with
+    /// keep_math_identifiers before and after it
+    fn begin_kept_math_prose(&mut self) {
+        self.separate_math_word();
+        self.math_identifier_ended = self.opts.keep_math_identifiers;
+        self.in_math_identifier = false;
+        self.begin_math_prose();
+    }
+
+    /// Handle a command in math: detex.l:204 - command symbols like \leq,
+    /// \geq, etc. are verbs. Not in detex.l: the argument of \tag and
+    /// \tag*, and of the invisible \phantom, \hphantom and \vphantom, is
+    /// dropped so a relation in it isn't taken for a verb.
+    fn math_command(&mut self, cmd: &str) {
+        if self.keep_math_words(cmd) {
+            return;
+        }
+        if cmd == "tag" {
+            self.match_optional_star();
+            self.skip_brace_arg();
+        } else if matches!(cmd, "phantom" | "hphantom" | "vphantom") {
+            self.skip_brace_arg();
+        } else if let Some(shape) = self.math_shape.as_mut() {
+            // Not in detex.l: replace_grammar goes by exact names, so \int
+            // is arithmetic rather than \in and \infty is no relation
+            if is_math_operator(cmd) {
+                shape.operators += 1;
+            } else if is_relation(cmd) {
+                shape.relations += 1;
+            }
+        } else if is_verb_symbol(cmd) {
+            self.verb_noun();
+        }
+    }
+
     /// detex.l:451-456 - Control state (after unknown \command)
     /// <Control>\\[a-zA-Z@]+              IGNORE;
     /// <Control>[a-zA-Z@0-9]*[-'=`][^ \t\n{]*  IGNORE;
@@ -1329,7 +2666,7 @@ impl<W: Write> Detex<W> {
     /// <Control>[ \t]*[{]+                {++currBracesLevel;BEGIN Normal; IGNORE;}
     /// <Control>[ \t]*                    {BEGIN Normal; IGNORE;}
     /// <Control>.                         {yyless(0);BEGIN Normal;}
-    fn process_control(&mut self) -> Result<(), String> {
+    fn process_control(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
             Some('\n') => self.state = State::Normal,
             Some(c) if c.is_whitespace() => {
@@ -1366,7 +2703,7 @@ impl<W: Write> Detex<W> {
     /// <IncludeOnly>"}"            {if (csbIncList==0) rgsbIncList[csbIncList++]='\0'; BEGIN Normal;}
     /// <IncludeOnly>"\n"+          NEWLINE;
     /// <IncludeOnly>.              ;
-    fn process_include_only(&mut self) -> Result<(), String> {
+    fn process_include_only(&mut self) -> Result<(), DetexError> {
         self.skip_whitespace();
         match self.peek_char() {
             Some('{') => {
@@ -1406,11 +2743,19 @@ impl<W: Write> Detex<W> {
     /// <Input>[^{ \t\n}]+   {InputFile(yytext); BEGIN Normal;}
     /// <Input>"\n"+         NEWLINE;
     /// <Input>.             ;
-    fn process_input(&mut self) -> Result<(), String> {
+    ///
+    /// Not in detex.l: a braced name is read up to its matching brace, so
+    /// it may contain spaces, and the closing brace is consumed with it.
+    fn process_input(&mut self) -> Result<(), DetexError> {
         self.skip_whitespace();
         match self.peek_char() {
             Some('{') => {
                 self.next_char();
+                let filename = self.read_raw_group().trim().to_string();
+                if !filename.is_empty() {
+                    self.input_file(&filename)?;
+                }
+                self.state = State::Normal;
             }
             Some('\n') => {
                 self.newline();
@@ -1437,15 +2782,43 @@ impl<W: Write> Detex<W> {
     /// <LaEnv>"\\end"  {LaBEGIN LaEnd; IGNORE;}
     /// <LaEnv>"\n"+    ;  (newlines are consumed but not processed)
     /// <LaEnv>.        {INCRLINENO;}
-    fn process_la_env(&mut self) -> Result<(), String> {
+    ///
+    /// Not in detex.l: the prose argument of \intertext, and with
+    /// keep_math_text of \text and \mbox in math environments, is
+    /// processed as Normal text, returning to LaEnv at its closing brace.
+    fn process_la_env(&mut self) -> Result<(), DetexError> {
+        if let Some(c) = self.peek_char()
+            && is_math_env(&self.current_ignored_env)
+        {
+            self.keep_math_identifier(c);
+        }
         match self.peek_char() {
             Some('\\') => {
                 self.next_char();
                 if self.try_match("end") {
                     self.la_begin(State::LaEnd);
                     self.ignore();
-                } else {
-                    // Consumed '\' - track it (no newline)
+                    return Ok(());
+                }
+                let cmd = self.read_command_name();
+                if cmd == "begin" {
+                    // Not in detex.l: count nested environments of the same
+                    // name so the inner \end doesn't close the outer one
+                    self.skip_whitespace();
+                    if self.try_match("{") {
+                        self.skip_whitespace();
+                        if self.read_env_name() == self.current_ignored_env {
+                            self.ignored_env_depth += 1;
+                        }
+                    }
+                } else if is_intertext(&cmd) {
+                    self.begin_math_prose();
+                } else if cmd == "label" && self.metadata.is_some() {
+                    // Not in detex.l: labels of dropped equations are kept
+                    // for extract
+                    self.record_metadata(&cmd);
+                } else if is_math_env(&self.current_ignored_env) {
+                    self.keep_math_words(&cmd);
                 }
             }
             Some('\n') => {
@@ -1471,28 +2844,40 @@ impl<W: Write> Detex<W> {
     /// <LaEnd>{W}   {if (EndEnv(yytext)) BEGIN Normal; IGNORE;}
     /// <LaEnd>"}"   {BEGIN LaEnv; IGNORE;}
     /// <LaEnd>.     {INCRLINENO;}
-    fn process_la_end(&mut self) -> Result<(), String> {
+    fn process_la_end(&mut self) -> Result<(), DetexError> {
         self.skip_whitespace();
         match self.peek_char() {
             Some('{') => {
                 self.next_char();
                 self.skip_whitespace();
-                let env = self.read_command_name();
+                let env = self.read_env_name();
                 self.skip_whitespace();
-                // Don't consume the '}' here - let it be matched separately
-                // to match opendetex behavior where '}' in LaEnd calls IGNORE
 
-                if self.end_env(&env) {
+                if self.end_env(&env) && self.ignored_env_depth > 0 {
+                    self.ignored_env_depth -= 1;
+                    self.state = State::LaEnv;
+                } else if self.end_env(&env) {
+                    // detex.l leaves this '}' to Normal, which decrements a
+                    // brace level that \begin{env} never incremented and can
+                    // close an enclosing \footnote early. Consume it instead.
+                    self.try_match("}");
                     self.state = State::Normal;
+                    self.end_math_identifier();
                 } else {
+                    // Don't consume the '}' here - let it be matched separately
+                    // to match opendetex behavior where '}' in LaEnd calls IGNORE
                     self.state = State::LaEnv;
                 }
                 self.ignore();
             }
             Some(c) if c.is_ascii_alphabetic() => {
-                let env = self.read_command_name();
-                if self.end_env(&env) {
+                let env = self.read_env_name();
+                if self.end_env(&env) && self.ignored_env_depth > 0 {
+                    self.ignored_env_depth -= 1;
+                    self.state = State::LaEnv;
+                } else if self.end_env(&env) {
                     self.state = State::Normal;
+                    self.end_math_identifier();
                 }
                 self.ignore();
             }
@@ -1517,16 +2902,15 @@ impl<W: Write> Detex<W> {
     /// <LaDisplay>"\n"          NEWLINE;
     /// <LaDisplay>{VERBSYMBOL}  VERBNOUN;
     /// <LaDisplay>.             ;
-    fn process_la_display(&mut self) -> Result<(), String> {
+    fn process_la_display(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
             Some('\\') => {
                 if self.try_match("]") {
                     self.state = State::Normal;
+                    self.end_math();
                 } else {
                     let cmd = self.read_command_name();
-                    if is_verb_symbol(&cmd) {
-                        self.verb_noun();
-                    }
+                    self.math_command(&cmd);
                 }
             }
             Some('\n') => self.newline(),
@@ -1541,16 +2925,15 @@ impl<W: Write> Detex<W> {
     /// <LaFormula>"\n"          NEWLINE;
     /// <LaFormula>{VERBSYMBOL}  VERBNOUN;
     /// <LaFormula>.             ;
-    fn process_la_formula(&mut self) -> Result<(), String> {
+    fn process_la_formula(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
             Some('\\') => {
                 if self.try_match(")") {
                     self.state = State::Normal;
+                    self.end_math();
                 } else {
                     let cmd = self.read_command_name();
-                    if is_verb_symbol(&cmd) {
-                        self.verb_noun();
-                    }
+                    self.math_command(&cmd);
                 }
             }
             Some('\n') => self.newline(),
@@ -1565,7 +2948,7 @@ impl<W: Write> Detex<W> {
     /// <LaInclude>[^{ \t\n}]+   {IncludeFile(yytext); BEGIN Normal;}
     /// <LaInclude>"\n"+         NEWLINE;
     /// <LaInclude>.             ;
-    fn process_la_include(&mut self) -> Result<(), String> {
+    fn process_la_include(&mut self) -> Result<(), DetexError> {
         self.skip_whitespace();
         match self.peek_char() {
             Some('{') => {
@@ -1602,7 +2985,7 @@ impl<W: Write> Detex<W> {
     ///                              }
     ///                            }
     /// <LaMacro>.                 ;
-    fn process_la_macro(&mut self) -> Result<(), String> {
+    fn process_la_macro(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
             // detex.l:487
             Some('[') => self.state = State::LaOptArg,
@@ -1648,7 +3031,7 @@ impl<W: Write> Detex<W> {
     /// detex.l:497-498 - LaOptArg state (inside optional [...] for LaMacro)
     /// <LaOptArg>"\]"    BEGIN LaMacro;
     /// <LaOptArg>[^\]]*  ;
-    fn process_la_opt_arg(&mut self) -> Result<(), String> {
+    fn process_la_opt_arg(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
             // detex.l:497
             Some(']') => self.state = State::LaMacro,
@@ -1677,7 +3060,7 @@ impl<W: Write> Detex<W> {
     ///                   }
     /// <LaMacro2>"}"     { cOpenBrace--; }
     /// <LaMacro2>.       ;
-    fn process_la_macro2(&mut self) -> Result<(), String> {
+    fn process_la_macro2(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
             // detex.l:500
             Some('[') => self.state = State::LaOptArg2,
@@ -1685,12 +3068,14 @@ impl<W: Write> Detex<W> {
             Some('{') => {
                 if self.open_braces == 0 {
                     self.args_count = self.args_count.saturating_sub(1);
-                    if self.args_count == 0 {
-                        self.state = State::Normal;
-                        self.open_braces = self.open_braces.wrapping_sub(1);
-                    }
                 }
-                self.open_braces += 1;
+                if self.open_braces == 0 && self.args_count == 0 {
+                    // detex.l decrements cOpenBrace here and increments it
+                    // again below, leaving it unchanged
+                    self.state = State::Normal;
+                } else {
+                    self.open_braces += 1;
+                }
             }
             // detex.l:511
             Some('}') => self.open_braces = self.open_braces.saturating_sub(1),
@@ -1710,7 +3095,7 @@ impl<W: Write> Detex<W> {
     /// detex.l:513-514 - LaOptArg2 state (inside optional [...] for LaMacro2)
     /// <LaOptArg2>"\]"  BEGIN LaMacro2;
     /// <LaOptArg2>.     ;
-    fn process_la_opt_arg2(&mut self) -> Result<(), String> {
+    fn process_la_opt_arg2(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
             // detex.l:513
             Some(']') => self.state = State::LaMacro2,
@@ -1727,26 +3112,22 @@ impl<W: Write> Detex<W> {
         Ok(())
     }
 
-    /// detex.l:225-227 - LaVerbatim state (inside verbatim environment)
+    /// detex.l:225-227 - LaVerbatim state (inside verbatim environment, or
+    /// lstlisting, minted and Verbatim which aren't in detex.l)
     /// <LaVerbatim>"\\end"{S}"{"{S}"verbatim"{S}"}"  BEGIN Normal; IGNORE;
     /// <LaVerbatim>[^\\]+                            ECHO;
     /// <LaVerbatim>.                                 ECHO;
-    fn process_la_verbatim(&mut self) -> Result<(), String> {
+    fn process_la_verbatim(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
-            // detex.l:225 - check for \end{verbatim}
+            // detex.l:225 - check for \end{verbatim}, or the end of the
+            // lstlisting, minted or Verbatim environment being echoed
             Some('\\') => {
-                if self.try_match("end") {
-                    self.skip_whitespace();
-                    if self.try_match("{") {
-                        self.skip_whitespace();
-                        if self.try_match("verbatim") {
-                            self.skip_whitespace();
-                            self.try_match("}");
-                            self.state = State::Normal;
-                            self.ignore();
-                            return Ok(());
-                        }
-                    }
+                let env = self.verbatim_env.clone();
+                if let Some(len) = self.end_of_env_len(&env) {
+                    self.skip_chars(len);
+                    self.state = State::Normal;
+                    self.ignore();
+                    return Ok(());
                 }
                 // detex.l:227 - if not \end{verbatim}, echo the backslash
                 let _ = write!(self.output, "\\");
@@ -1758,12 +3139,85 @@ impl<W: Write> Detex<W> {
         Ok(())
     }
 
+    /// Consume `n` characters, counting any newlines among them
+    fn skip_chars(&mut self, n: usize) {
+        for _ in 0..n {
+            if self.next_char() == Some('\n')
+                && let Some(source) = self.current_source_mut()
+            {
+                source.incr_line();
+            }
+        }
+    }
+
+    /// Length of `end{env}` (with optional whitespace around env) at the
+    /// current position, just after a backslash
+    fn end_of_env_len(&mut self, env: &str) -> Option<usize> {
+        let lookahead: Vec<char> = self
+            .current_source_mut()?
+            .peek_ahead(env.len() + 32)
+            .chars()
+            .collect();
+        let skip_space = |mut i: usize| {
+            while lookahead.get(i).is_some_and(|c| c.is_whitespace()) {
+                i += 1;
+            }
+            i
+        };
+        let matches_at = |i: usize, text: &str| {
+            text.chars()
+                .enumerate()
+                .all(|(j, c)| lookahead.get(i + j) == Some(&c))
+        };
+
+        if !matches_at(0, "end") {
+            return None;
+        }
+        let mut i = skip_space(3);
+        if !matches_at(i, "{") {
+            return None;
+        }
+        i = skip_space(i + 1);
+        if !matches_at(i, env) {
+            return None;
+        }
+        i = skip_space(i + env.chars().count());
+        if !matches_at(i, "}") {
+            return None;
+        }
+        Some(i + 1)
+    }
+
+    /// LaComment state (inside \begin{comment}), not in detex.l. Everything
+    /// up to \end{comment} is dropped without matching braces, since the
+    /// body need not be valid LaTeX.
+    fn process_la_comment(&mut self) -> Result<(), DetexError> {
+        match self.next_char() {
+            Some('\\') => {
+                let env = self.verbatim_env.clone();
+                if let Some(len) = self.end_of_env_len(&env) {
+                    self.skip_chars(len);
+                    self.state = State::Normal;
+                    self.ignore();
+                }
+            }
+            Some('\n') => {
+                if let Some(source) = self.current_source_mut() {
+                    source.incr_line();
+                }
+                self.at_column_zero = true;
+            }
+            Some(_) | None => {}
+        }
+        Ok(())
+    }
+
     /// detex.l:300-303 - LaPicture state (parsing \includegraphics{filename})
     /// <LaPicture>"{"         ;
     /// <LaPicture>[^{}]+      { if(fShowPictures) { printf("<Picture %s>", yytext); } }
     /// <LaPicture>"\}"{S}"\n"+  { BEGIN Normal; INCRLINENO; }
     /// <LaPicture>"\}"        BEGIN Normal;
-    fn process_la_picture(&mut self) -> Result<(), String> {
+    fn process_la_picture(&mut self) -> Result<(), DetexError> {
         match self.next_char() {
             // detex.l:300 - skip opening brace
             Some('{') => {}
@@ -1802,11 +3256,243 @@ impl<W: Write> Detex<W> {
     }
 }
 
-/// Check if a LaTeX command name is a verb symbol
-/// detex.l:204 - VERBSYMBOL pattern includes these commands
-fn is_verb_symbol(cmd: &str) -> bool {
+/// Strip TeX/LaTeX commands from a string and return the plain text.
+///
+/// Nothing is read from stdin or the filesystem: \input, \include and
+/// \subfile are skipped as with the -n flag.
+pub fn detex_str(input: &str, mut opts: Options) -> Result<String, DetexError> {
+    opts.no_follow = true;
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex.process_reader(std::io::Cursor::new(input.as_bytes().to_vec()), "<string>")?;
+    drop(detex);
+    Ok(String::from_utf8(output).expect("output is written from chars"))
+}
+
+/// Strip TeX/LaTeX commands from a string, and gather its title, authors,
+/// date, abstract, sections, labels, citations and word count.
+///
+/// As with `detex_str`, \input, \include and \subfile are skipped. Where
+/// `detex_str` fails on an unterminated \verb, the text and metadata stop
+/// there instead.
+pub fn extract(input: &str, mut opts: Options) -> ExtractResult {
+    opts.no_follow = true;
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex.metadata = Some(ExtractResult::default());
+    let _ = detex.process_reader(std::io::Cursor::new(input.as_bytes().to_vec()), "<string>");
+    let mut result = detex.metadata.take().unwrap_or_default();
+    drop(detex);
+    result.text = String::from_utf8(output).expect("output is written from chars");
+    result
+}
+
+/// Text of a metadata argument, detexed as LaTeX with its whitespace
+/// collapsed
+fn metadata_text(raw: &str) -> String {
+    let opts = Options {
+        latex: true,
+        no_follow: true,
+        ..Options::default()
+    };
+    let text = detex_str(raw, opts).unwrap_or_default();
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Scan `text` for the argument `peek_brace_arg` looks for: None if text
+/// ends before it is known, Some(None) if there is no brace argument
+fn scan_brace_arg(text: &str) -> Option<Option<String>> {
+    let mut chars = text.chars();
+    loop {
+        match chars.next()? {
+            '*' => {}
+            '[' => while chars.next()? != ']' {},
+            c if c.is_whitespace() => {}
+            '{' => break,
+            _ => return Some(None),
+        }
+    }
+    let mut arg = String::new();
+    let mut depth = 1;
+    loop {
+        let c = chars.next()?;
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(Some(arg));
+                }
+            }
+            _ => {}
+        }
+        arg.push(c);
+    }
+}
+
+/// Citation commands of LaTeX, natbib and biblatex whose keys `extract`
+/// records
+fn is_citation(cmd: &str) -> bool {
+    matches!(
+        cmd,
+        "cite"
+            | "nocite"
+            | "citep"
+            | "citet"
+            | "citealp"
+            | "citealt"
+            | "citeauthor"
+            | "citeyear"
+            | "parencite"
+            | "textcite"
+            | "autocite"
+            | "footcite"
+    )
+}
+
+/// Remove \thanks{...} notes from an author
+fn strip_thanks(author: &str) -> String {
+    let mut rest = author;
+    let mut stripped = String::new();
+    while let Some(start) = rest.find("\\thanks") {
+        stripped.push_str(&rest[..start]);
+        let after = &rest[start + "\\thanks".len()..];
+        let mut depth = 0;
+        let end = after
+            .char_indices()
+            .find(|&(_, c)| {
+                match c {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => {}
+                }
+                depth == 0 && c == '}'
+            })
+            .map_or(after.len(), |(i, _)| i + 1);
+        rest = &after[end..];
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+/// Check if a command carries prose between the lines of a math
+/// environment: amsmath's \intertext or mathtools' \shortintertext
+fn is_intertext(cmd: &str) -> bool {
+    matches!(cmd, "intertext" | "shortintertext")
+}
+
+/// Check if an environment lays out its body in rows ended by \\
+fn is_table_env(env: &str) -> bool {
+    matches!(
+        env,
+        "tabular" | "tabular*" | "tabularx" | "array" | "longtable"
+    )
+}
+
+/// Check if a command holds prose inside math, or an operator name
+/// spelled as a word, kept with keep_math_text
+fn is_math_text(cmd: &str) -> bool {
+    matches!(cmd, "text" | "mbox" | "operatorname")
+}
+
+/// Check if a command sets letters in a math alphabet, kept with
+/// keep_math_text
+fn is_math_alphabet(cmd: &str) -> bool {
+    matches!(cmd, "mathbb" | "mathcal" | "mathfrak" | "mathscr")
+}
+
+/// Check if a math command is arithmetic, for replace_grammar
+fn is_math_operator(cmd: &str) -> bool {
+    matches!(
+        cmd,
+        "frac"
+            | "dfrac"
+            | "tfrac"
+            | "sqrt"
+            | "sum"
+            | "prod"
+            | "int"
+            | "oint"
+            | "cdot"
+            | "times"
+            | "div"
+            | "pm"
+            | "mp"
+            | "cup"
+            | "cap"
+            | "circ"
+            | "oplus"
+            | "otimes"
+    )
+}
+
+/// Check if a command is horizontal space in math, a word break between
+/// words kept with keep_math_text
+fn is_math_space(cmd: &str) -> bool {
     matches!(
         cmd,
-        "leq" | "geq" | "in" | "subseteq" | "subset" | "supset" | "sim" | "neq" | "mapsto"
+        "quad" | "qquad" | "enspace" | "thinspace" | "medspace" | "thickspace"
+    )
+}
+
+/// Check if an ignored environment is display math, whose \text prose
+/// is kept with keep_math_text
+fn is_math_env(env: &str) -> bool {
+    let base = env.strip_suffix('*').unwrap_or(env);
+    matches!(
+        base,
+        "equation"
+            | "align"
+            | "alignat"
+            | "flalign"
+            | "gather"
+            | "multline"
+            | "eqnarray"
+            | "displaymath"
+            | "math"
+            | "array"
     )
 }
+
+/// detex.l:204 - commands in the VERBSYMBOL pattern
+const VERB_SYMBOLS: [&str; 9] = [
+    "leq", "geq", "in", "subseteq", "subset", "supset", "sim", "neq", "mapsto",
+];
+
+/// Not in detex.l: mathtools' colon-equals relations and other definition
+/// and assignment operators
+const ASSIGNMENT_SYMBOLS: [&str; 14] = [
+    "coloneq",
+    "coloneqq",
+    "Coloneq",
+    "Coloneqq",
+    "eqcolon",
+    "Eqcolon",
+    "eqqcolon",
+    "Eqqcolon",
+    "defeq",
+    "triangleq",
+    "doteq",
+    "doteqdot",
+    "equiv",
+    "gets",
+];
+
+/// Check if a LaTeX command name is a verb symbol
+/// detex.l:204 - VERBSYMBOL pattern includes these commands
+///
+/// Flex matches VERBSYMBOL against the input without requiring the command
+/// to end there, so a command that merely starts with one of them (`\int`,
+/// `\leqslant`) counts as well.
+///
+/// Not in detex.l: the assignment symbols are verb symbols too. They are
+/// matched by their exact name, since they have no flex rule to mimic.
+fn is_verb_symbol(cmd: &str) -> bool {
+    VERB_SYMBOLS.iter().any(|verb| cmd.starts_with(verb)) || ASSIGNMENT_SYMBOLS.contains(&cmd)
+}
+
+/// Check if a math command is a relation, for replace_grammar: a verb
+/// symbol by its exact name, without the prefix matching of flex
+fn is_relation(cmd: &str) -> bool {
+    VERB_SYMBOLS.contains(&cmd) || ASSIGNMENT_SYMBOLS.contains(&cmd)
+}
@@ -8,15 +8,42 @@
 //! ```
 //! use detex::{Detex, Options};
 //!
-//! let opts = Options::default();
+//! let opts = Options::default()
+//!     .with_latex(true)
+//!     .with_no_follow(true)
+//!     .with_env_ignore("equation,verbatim");
 //! let mut output = Vec::new();
 //! let mut detex = Detex::new(opts, &mut output);
-//! // Process files or stdin...
+//! detex
+//!     .process_reader(std::io::Cursor::new(b"See \\cite{knuth}.\n".to_vec()), "doc")
+//!     .unwrap();
+//! drop(detex);
+//! assert_eq!(output, b"See.\n");
+//! ```
+//!
+//! To strip a string directly:
+//!
+//! ```
+//! use detex::{Options, detex_str};
+//!
+//! let text = detex_str("Some \\emph{emphasized} text.\n", Options::default()).unwrap();
+//! assert_eq!(text, "Some emphasized text.\n");
 //! ```
 
+mod accents;
 mod config;
+mod error;
 mod file_handler;
+mod json;
 mod lexer;
+mod output;
+#[cfg(feature = "parallel")]
+mod parallel;
 
-pub use config::Options;
-pub use lexer::Detex;
+pub use config::{ModeDefaults, Options, TildeOutput, mode_from_program_name};
+pub use error::DetexError;
+pub use file_handler::{FileResolver, FsResolver};
+pub use json::summary_json;
+pub use lexer::{Detex, ExtractResult, SourceSpan, detex_str, extract};
+#[cfg(feature = "parallel")]
+pub use parallel::{FileOutput, detex_files};
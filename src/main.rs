@@ -1,7 +1,8 @@
-use std::io::{self, BufWriter};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
 use std::process;
 
-use detex::{Detex, Options};
+use detex::{Detex, DetexError, FileResolver, FsResolver, Options};
 
 const VERSION: &str = "0.2.0";
 
@@ -12,63 +13,97 @@ fn main() {
         .and_then(|s| s.to_str())
         .unwrap_or("detex");
 
-    // Check if invoked as 'delatex'
-    let mut opts = Options::default();
-    if program_name == "delatex" {
-        opts.latex = true;
+    let (opts, mut files) = match Options::from_args(&args) {
+        Ok(parsed) => parsed,
+        Err(message) => error_exit(program_name, &message),
+    };
+    if let Some(option) = &opts.unknown_option {
+        eprintln!(
+            "{}: warning: unknown option ignored {}",
+            program_name, option
+        );
+        usage_exit(program_name);
     }
+    if opts.show_help {
+        usage_exit(program_name);
+    }
+    if opts.show_version {
+        version_exit();
+    }
+    let warn_summary = opts.warn_summary;
 
-    // Set up input paths
-    opts.setup_input_paths();
-
-    // Parse command line arguments
-    let mut files: Vec<String> = Vec::new();
-    let mut i = 1;
-
-    while i < args.len() {
-        let arg = &args[i];
-
-        if arg.starts_with('-') && arg.len() > 1 {
-            let chars: Vec<char> = arg[1..].chars().collect();
-            let mut j = 0;
-
-            while j < chars.len() {
-                match chars[j] {
-                    'c' => opts.cite = true,
-                    'e' => {
-                        i += 1;
-                        if i >= args.len() {
-                            error_exit(program_name, "-e option requires an argument");
-                        }
-                        opts = opts.with_env_ignore(&args[i]);
-                    }
-                    'l' => opts.latex = true,
-                    'n' => opts.no_follow = true,
-                    'r' => opts.replace = true,
-                    's' => opts.space = true,
-                    't' => opts.force_tex = true,
-                    'w' => opts.word = true,
-                    '1' => opts.src_loc = true,
-                    'v' => version_exit(),
-                    'h' | '?' => usage_exit(program_name),
-                    c => {
-                        eprintln!("{}: warning: unknown option ignored -{}", program_name, c);
-                        usage_exit(program_name);
+    // Buffer the output, which goes to stdout unless -o names a file
+    let mut output: Box<dyn Write> = match &opts.output_path {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(e) => error_exit(program_name, &format!("can't create {}: {}", path, e)),
+        },
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let quiet = opts.suppress_warnings;
+    let count_only = opts.count_only;
+
+    // A "-" names stdin, which is read once however often it is given
+    let mut read_stdin = false;
+    files.retain(|file| file != "-" || !std::mem::replace(&mut read_stdin, true));
+
+    // --summary-json reads each input whole and prints its metadata
+    if opts.summary_json {
+        let mut failed = false;
+        if files.is_empty() {
+            files.push("-".to_string());
+        }
+        for file in &files {
+            match summarize(file, &opts) {
+                Ok(summary) => {
+                    let _ = output.write_all(summary.as_bytes());
+                }
+                Err(e) => {
+                    failed = true;
+                    if !quiet {
+                        eprintln!("{}: warning: {}", program_name, e);
                     }
                 }
-                j += 1;
             }
-        } else {
-            files.push(arg.clone());
         }
+        let _ = output.flush();
+        if failed {
+            process::exit(1);
+        }
+        return;
+    }
 
-        i += 1;
+    // With -j, files are processed on several threads, each from a fresh
+    // state. -W counts unique words across all the files, so it doesn't.
+    #[cfg(feature = "parallel")]
+    if opts.jobs != 1 && !count_only && files.len() > 1 {
+        let mut warnings = Vec::new();
+        let mut failed = false;
+        for file in detex::detex_files(&files, &opts) {
+            let _ = output.write_all(&file.text);
+            failed |= file.result.is_err();
+            let error = file.result.err().map(|e| e.to_string());
+            for warning in file.warnings.into_iter().chain(error) {
+                if !quiet {
+                    eprintln!("{}: warning: {}", program_name, warning);
+                }
+                warnings.push(warning);
+            }
+        }
+        let _ = output.flush();
+        if warn_summary {
+            print_warning_summary(program_name, &warnings);
+        }
+        if failed {
+            process::exit(1);
+        }
+        return;
     }
 
-    // Create buffered stdout for better performance
-    let stdout = io::stdout();
-    let output = BufWriter::new(stdout.lock());
-    let mut detex = Detex::new(opts, output);
+    let mut detex = Detex::new(opts, &mut output);
+    let mut file_warnings: Vec<String> = Vec::new();
+    // Every file is tried, but the exit status shows if any failed
+    let mut failed = false;
 
     if files.is_empty() {
         if let Err(e) = detex.process_stdin() {
@@ -77,29 +112,111 @@ fn main() {
         }
     } else {
         for file in files {
-            if let Err(e) = detex.process_file(&file) {
-                eprintln!("{}: warning: {}", program_name, e);
+            let result = if file == "-" {
+                detex.process_stdin()
+            } else {
+                detex.process_file(&file)
+            };
+            if let Err(e) = result {
+                failed = true;
+                if !quiet {
+                    eprintln!("{}: warning: {}", program_name, e);
+                }
+                file_warnings.push(e.to_string());
             }
         }
     }
+
+    let counts = (detex.word_count(), detex.unique_word_count());
+    let mut warnings = detex.warnings().to_vec();
+    warnings.extend(file_warnings);
+    drop(detex);
+
+    if count_only {
+        let _ = writeln!(output, "{} words, {} unique", counts.0, counts.1);
+    }
+    let _ = output.flush();
+
+    if warn_summary {
+        print_warning_summary(program_name, &warnings);
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+/// Read a whole input, "-" for stdin, and give its --summary-json line
+fn summarize(file: &str, opts: &Options) -> Result<String, DetexError> {
+    let (mut reader, path): (Box<dyn Read>, String) = if file == "-" {
+        (Box::new(io::stdin()), "<stdin>".to_string())
+    } else {
+        FsResolver
+            .resolve(file, opts)
+            .ok_or_else(|| DetexError::FileNotFound(file.to_string()))?
+    };
+    let mut input = String::new();
+    reader
+        .read_to_string(&mut input)
+        .map_err(|source| DetexError::Io { path, source })?;
+    let result = detex::extract(&input, opts.clone());
+    Ok(detex::summary_json(&result))
+}
+
+/// Print the --warn-summary count and list of warnings
+fn print_warning_summary(program_name: &str, warnings: &[String]) {
+    eprintln!("{}: {} warning(s)", program_name, warnings.len());
+    for warning in warnings {
+        eprintln!("  {}", warning);
+    }
 }
 
 fn usage_exit(program_name: &str) -> ! {
     println!(
-        "\n{} [ -clnrstw1v ] [ -e environment-list ] [ filename[.tex] ... ]",
+        "\n{} [ -clnrstwW1vQ ] [ -e environment-list ] [ -j jobs ] [ -o file ] [ filename[.tex] ... ]",
         program_name
     );
     println!("Strip (La)TeX commands from the input.\n");
-    println!("  -c  echo LaTeX \\cite, \\ref, and \\pageref values");
-    println!("  -e  <env-list> list of LaTeX environments to ignore");
-    println!("  -l  force latex mode");
-    println!("  -n  do not follow \\input, \\include and \\subfile");
-    println!("  -r  replace math with \"noun\" and \"noun verbs noun\" to preserve grammar");
-    println!("  -s  replace control sequences with space");
-    println!("  -t  force tex mode");
-    println!("  -w  word only output");
-    println!("  -1  outputs the original file name and line number in the beginning of each line");
-    println!("  -v  show program version and exit");
+    println!("  -c  echo LaTeX \\cite, \\ref, and \\pageref values (also --cite)");
+    println!("  -e  <env-list> list of LaTeX environments to ignore (also --environments=<list>)");
+    println!(
+        "  -j  <n> process files on n threads, 0 for one per CPU, with the parallel feature (also --jobs)"
+    );
+    println!(
+        "      each file then starts afresh, so LaTeX mode and \\includeonly don't carry over"
+    );
+    println!("      and the output can differ; -W still processes the files in turn");
+    println!("  -l  force latex mode (also --latex)");
+    println!("  -o  <file> write the output to file instead of stdout (also --output)");
+    println!("  -n  do not follow \\input, \\include and \\subfile (also --no-follow)");
+    println!(
+        "  -r  replace math with \"noun\" and \"noun verbs noun\" to preserve grammar (also --replace)"
+    );
+    println!("  -s  replace control sequences with space (also --space)");
+    println!("  -t  force tex mode (also --tex)");
+    println!("  -w  word only output (also --word)");
+    println!("  -W  print only \"<words> words, <unique> unique\" (unique ignores case)");
+    println!(
+        "  -1  outputs the original file name and line number in the beginning of each line (also --source-loc)"
+    );
+    println!("  -v  show program version and exit (also --version)");
+    println!("  -Q  do not print warnings (also --quiet)");
+    println!("  --column  like -1, with the column as well: file:line:column:");
+    println!("  --replace-with <word>  like -r, but replace all math with <word>");
+    println!(
+        "  --replace-grammar  like -r, with \"it\", \"the expression\" or \"noun equals noun\""
+    );
+    println!("  --lines <a:b>  only output text from source lines a to b");
+    println!("  --max-line-length <n>  wrap output lines at word boundaries to n columns");
+    println!("  --squeeze  collapse runs of spaces, e.g. those left by -s");
+    println!("  --detect-encoding  read UTF-8 or UTF-16 with a BOM, UTF-8, or else Latin-1");
+    println!("  --warn-summary  print a count and list of warnings at the end");
+    println!(
+        "  --summary-json  print the title, authors, date, abstract, sections and word count as JSON"
+    );
+    println!(
+        "  --json-lines  print each word as a line of JSON: {{\"text\", \"file\", \"line\", \"col\"}}"
+    );
+    println!("\nA filename of - reads stdin, and @file reads file names from file, one per line.");
     println!("\nRust port of opendetex: https://github.com/pkubowicz/opendetex");
     process::exit(0);
 }
@@ -0,0 +1,194 @@
+//! Output sink for detex.
+
+use std::io::{self, Write};
+
+/// Writer between the lexer and the caller's output.
+///
+/// Remembers whether the current output line is still blank, collapses
+/// runs of spaces when squeezing (--squeeze), drops everything while muted
+/// (outside --lines) or discarding (-W), and wraps lines at word boundaries when given a
+/// width (--max-line-length). Verbatim text is never wrapped.
+pub struct OutputWriter<W: Write> {
+    inner: W,
+    squeeze: bool,
+    last_space: bool,
+    line_blank: bool,
+    muted: bool,
+    /// Drop everything for good, whatever the muting
+    discard: bool,
+    /// Bytes accepted so far, after squeezing and before wrapping
+    position: usize,
+    wrap_at: Option<usize>,
+    verbatim: bool,
+    /// Characters on the current line already passed to `inner`
+    column: usize,
+    /// Spaces and word not yet written, held back until the word ends
+    pending_space: Vec<u8>,
+    pending_word: Vec<u8>,
+}
+
+impl<W: Write> OutputWriter<W> {
+    pub fn new(inner: W, squeeze: bool, wrap_at: Option<usize>) -> Self {
+        Self {
+            inner,
+            squeeze,
+            last_space: false,
+            line_blank: true,
+            muted: false,
+            discard: false,
+            position: 0,
+            wrap_at,
+            verbatim: false,
+            column: 0,
+            pending_space: Vec::new(),
+            pending_word: Vec::new(),
+        }
+    }
+
+    /// The writer output goes to
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// True if only whitespace has been written since the last newline
+    pub fn line_blank(&self) -> bool {
+        self.line_blank
+    }
+
+    /// True if the last character written was a space
+    pub fn ends_with_space(&self) -> bool {
+        self.last_space
+    }
+
+    /// Treat the current line as blank again, e.g. after a -1 prefix
+    pub fn reset_line(&mut self) {
+        self.line_blank = true;
+    }
+
+    /// Drop all output until unmuted
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Drop all output, for when only words are counted
+    pub fn set_discard(&mut self, discard: bool) {
+        self.discard = discard;
+    }
+
+    /// Offset in bytes of the next output, counted before wrapping
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// True while output is being dropped
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Write verbatim text as is, without wrapping
+    pub fn set_verbatim(&mut self, verbatim: bool) -> io::Result<()> {
+        if verbatim && !self.verbatim {
+            self.write_pending()?;
+        }
+        self.verbatim = verbatim;
+        Ok(())
+    }
+
+    /// Write the held back spaces and word, breaking the line before the
+    /// word if it doesn't fit.
+    fn write_pending(&mut self) -> io::Result<()> {
+        let width = self.wrap_at.unwrap_or(usize::MAX);
+        let word_len = char_count(&self.pending_word);
+        let space_len = char_count(&self.pending_space);
+        if word_len > 0 && self.column > 0 && self.column + space_len + word_len > width {
+            self.inner.write_all(b"\n")?;
+            self.column = 0;
+        } else {
+            self.inner.write_all(&self.pending_space)?;
+            self.column += space_len;
+        }
+        self.inner.write_all(&self.pending_word)?;
+        self.column += word_len;
+        self.pending_space.clear();
+        self.pending_word.clear();
+        Ok(())
+    }
+
+    fn write_wrapped(&mut self, b: u8) -> io::Result<()> {
+        if self.verbatim {
+            self.inner.write_all(&[b])?;
+            if b == b'\n' {
+                self.column = 0;
+            } else if !is_continuation(b) {
+                self.column += 1;
+            }
+            return Ok(());
+        }
+        match b {
+            b'\n' => {
+                self.write_pending()?;
+                self.inner.write_all(b"\n")?;
+                self.column = 0;
+            }
+            b' ' | b'\t' => {
+                if !self.pending_word.is_empty() {
+                    self.write_pending()?;
+                }
+                self.pending_space.push(b);
+            }
+            _ => self.pending_word.push(b),
+        }
+        Ok(())
+    }
+}
+
+/// Check for a UTF-8 continuation byte, which doesn't start a character
+fn is_continuation(b: u8) -> bool {
+    b & 0xc0 == 0x80
+}
+
+fn char_count(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| !is_continuation(b)).count()
+}
+
+impl<W: Write> Write for OutputWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.muted || self.discard {
+            return Ok(buf.len());
+        }
+        let mut kept = Vec::with_capacity(buf.len());
+        for &b in buf {
+            // Verbatim text keeps its spaces, as it does its line breaks
+            if self.squeeze && !self.verbatim && b == b' ' && self.last_space {
+                continue;
+            }
+            self.last_space = b == b' ';
+            if b == b'\n' {
+                self.line_blank = true;
+            } else if !b.is_ascii_whitespace() {
+                self.line_blank = false;
+            }
+            kept.push(b);
+        }
+        self.position += kept.len();
+        if self.wrap_at.is_some() {
+            for b in kept {
+                self.write_wrapped(b)?;
+            }
+        } else {
+            self.inner.write_all(&kept)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for OutputWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
@@ -0,0 +1,60 @@
+//! Processing independent files on several threads, with the `parallel`
+//! feature.
+
+use rayon::prelude::*;
+
+use crate::config::Options;
+use crate::error::DetexError;
+use crate::lexer::Detex;
+
+/// The result of processing one file with `detex_files`
+#[derive(Debug)]
+pub struct FileOutput {
+    /// The file as named
+    pub name: String,
+    /// Text output for the file
+    pub text: Vec<u8>,
+    /// Warnings issued while processing the file
+    pub warnings: Vec<String>,
+    /// Error that stopped processing of the file, if any
+    pub result: Result<(), DetexError>,
+}
+
+/// Process each file with its own `Detex` on up to `opts.jobs` threads,
+/// returning the outputs in the order of `files`.
+///
+/// Unlike processing the files in turn with one `Detex`, each file starts
+/// from a fresh state, so nothing (LaTeX mode, \includeonly, macros)
+/// carries over from one file to the next. Warnings are returned rather
+/// than printed. A file named "-" is read from stdin.
+pub fn detex_files(files: &[String], opts: &Options) -> Vec<FileOutput> {
+    let opts = Options {
+        suppress_warnings: true,
+        ..opts.clone()
+    };
+    let process = |name: &String| {
+        let mut text = Vec::new();
+        let mut detex = Detex::new(opts.clone(), &mut text);
+        let result = if name == "-" {
+            detex.process_stdin()
+        } else {
+            detex.process_file(name)
+        };
+        let warnings = detex.warnings().to_vec();
+        drop(detex);
+        FileOutput {
+            name: name.clone(),
+            text,
+            warnings,
+            result,
+        }
+    };
+
+    match rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.jobs)
+        .build()
+    {
+        Ok(pool) => pool.install(|| files.par_iter().map(process).collect()),
+        Err(_) => files.iter().map(process).collect(),
+    }
+}
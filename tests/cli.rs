@@ -0,0 +1,229 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run the detex binary with `args`, feeding `input` on stdin
+fn run_detex(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_detex"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to run detex");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().expect("Failed to wait for detex")
+}
+
+#[test]
+fn test_quiet_suppresses_warnings() {
+    let input = "Text \\input{no-such-file} more.\n";
+
+    let output = run_detex(&[], input);
+    assert!(!output.stderr.is_empty());
+
+    for flag in ["--quiet", "-Q"] {
+        let output = run_detex(&[flag], input);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "Text  more.\n");
+    }
+
+    let output = run_detex(&["--quiet", "no-such-file.tex"], "");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn test_word_count_summary() {
+    let output = run_detex(&["-W"], "One two, two \\emph{three}.\n");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "4 words, 3 unique\n"
+    );
+
+    let output = run_detex(
+        &["-W", "-l"],
+        "Hello\\footnote{note here} world \\verb|x y|\n",
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "4 words, 4 unique\n"
+    );
+}
+
+#[test]
+fn test_summary_json() {
+    let input = "\\documentclass{article}\n\\title{A \\emph{Study} of \"Things\"}\n\
+                 \\author{Ann Lee \\and Bo Chen\\thanks{MIT}}\n\\date{May 2024}\n\
+                 \\begin{document}\n\\maketitle\n\\begin{abstract}\nWe study \\textbf{things}\n\
+                 at length.\n\\end{abstract}\n\\section{Intro}\nSome text here.\n\
+                 \\subsection*{More}\nEnd.\n\\end{document}\n";
+    let output = run_detex(&["--summary-json"], input);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1);
+    let summary: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(
+        summary,
+        serde_json::json!({
+            "title": "A Study of \"Things\"",
+            "authors": ["Ann Lee", "Bo Chen"],
+            "date": "May 2024",
+            "abstract": "We study things at length.",
+            "sections": [{"level": 2, "title": "Intro"}, {"level": 3, "title": "More"}],
+            "word_count": 21,
+        })
+    );
+
+    let output = run_detex(&["--summary-json"], "Just text.\n");
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["title"], serde_json::Value::Null);
+    assert_eq!(summary["abstract"], serde_json::Value::Null);
+    assert_eq!(summary["word_count"], 2);
+}
+
+#[test]
+fn test_output_file() {
+    let dir = std::env::temp_dir().join(format!("detex-cli-output-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.tex"), "First \\emph{file}.\n").unwrap();
+    std::fs::write(dir.join("b.tex"), "Second file.\n").unwrap();
+    let out = dir.join("out.txt");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_detex"))
+        .current_dir(&dir)
+        .args(["-o", "out.txt", "a.tex", "b"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        std::fs::read_to_string(&out).unwrap(),
+        "First file.\nSecond file.\n"
+    );
+
+    let output = run_detex(&["--output", "no-such-dir/out.txt"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("can't create no-such-dir/out.txt")
+    );
+}
+
+#[test]
+fn test_long_options_match_short_ones() {
+    let input = "\\documentclass{article}\n\\begin{document}\nSee \\cite{knuth} and $x$.\n\\begin{equation}\ny = 1\n\\end{equation}\nDone.\n\\end{document}\n";
+    let short = run_detex(&["-c", "-r", "-e", "equation"], input);
+    assert!(short.status.success());
+
+    for args in [
+        &["--cite", "--replace", "--environments=equation"][..],
+        &["--cite", "--replace", "--environments", "equation"][..],
+    ] {
+        let long = run_detex(args, input);
+        assert!(long.status.success());
+        assert_eq!(long.stdout, short.stdout);
+    }
+
+    let long = run_detex(&["--max-line-length=4"], "one two three\n");
+    assert_eq!(String::from_utf8(long.stdout).unwrap(), "one\ntwo\nthree\n");
+}
+
+#[test]
+fn test_unknown_long_option() {
+    let output = run_detex(&["--bogus=1"], "");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr, "detex: warning: unknown option ignored --bogus\n");
+    assert!(
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .contains("Strip (La)TeX")
+    );
+}
+
+#[test]
+fn test_failed_file_sets_exit_status() {
+    let output = run_detex(
+        &["no-such-file.tex", "tests/latex/simple/01_simple_text.tex"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("can't open file no-such-file.tex")
+    );
+    assert!(!output.stdout.is_empty());
+
+    let output = run_detex(&["tests/latex/simple/01_simple_text.tex"], "");
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_dash_reads_stdin() {
+    let output = run_detex(
+        &["-", "tests/latex/simple/01_simple_text.tex", "-"],
+        "From \\emph{stdin}.\n",
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("From stdin.\n"));
+    assert_eq!(stdout.matches("From stdin.").count(), 1);
+
+    let output = run_detex(&["-1", "-"], "Hello\n");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "<stdin>:1: Hello\n"
+    );
+}
+
+#[test]
+fn test_double_dash_ends_options() {
+    let dir = std::env::temp_dir().join(format!("detex-cli-dashdash-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("-file.tex"), "Dashed \\emph{name}.\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_detex"))
+        .current_dir(&dir)
+        .args(["-l", "--", "-file.tex"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Dashed name.\n");
+}
+
+#[test]
+fn test_list_file() {
+    let dir = std::env::temp_dir().join(format!("detex-cli-list-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.tex"), "First \\emph{file}.\n").unwrap();
+    std::fs::write(dir.join("b.tex"), "Second file.\n").unwrap();
+    std::fs::write(dir.join("files.txt"), "# chapters\na.tex\n\n  b.tex\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_detex"))
+        .current_dir(&dir)
+        .arg("@files.txt")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "First file.\nSecond file.\n"
+    );
+
+    let output = run_detex(&["@no-such-list"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("can't read no-such-list")
+    );
+}
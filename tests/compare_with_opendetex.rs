@@ -81,8 +81,17 @@ fn get_tex_files(dir: &Path) -> Vec<PathBuf> {
     files
 }
 
+/// Fixtures with \cite, whose key -c echoes where opendetex drops it
+const CITE_FIXTURES: &[&str] = &["06_citations.tex", "09_nested_environments.tex"];
+
 /// Generic test runner that compares outputs with optional flags
 fn run_comparison_tests_in_dir(dir: &str, flags: &[&str]) {
+    run_comparison_tests_in_dir_except(dir, flags, &[]);
+}
+
+/// Like `run_comparison_tests_in_dir`, but skipping the files named in
+/// `skip`, whose output deliberately differs from opendetex with `flags`
+fn run_comparison_tests_in_dir_except(dir: &str, flags: &[&str], skip: &[&str]) {
     // Ensure opendetex is built before running tests
     ensure_opendetex_built();
 
@@ -91,7 +100,10 @@ fn run_comparison_tests_in_dir(dir: &str, flags: &[&str]) {
         .join("tests")
         .join("latex")
         .join(dir);
-    let test_files = get_tex_files(&test_dir);
+    let test_files: Vec<PathBuf> = get_tex_files(&test_dir)
+        .into_iter()
+        .filter(|file| !skip.iter().any(|name| file.ends_with(name)))
+        .collect();
 
     assert!(
         !test_files.is_empty(),
@@ -177,7 +189,7 @@ fn test_simple_latex_files() {
 
 #[test]
 fn test_simple_latex_files_cite_flag() {
-    run_comparison_tests_in_dir("simple", &["-c"]);
+    run_comparison_tests_in_dir_except("simple", &["-c"], CITE_FIXTURES);
 }
 
 #[test]
@@ -207,10 +219,24 @@ fn test_complex_latex_files_space_flag() {
 
 #[test]
 fn test_simple_latex_files_srcloc() {
-    run_comparison_tests_in_dir("simple", &["-e", "tabular", "-l", "-c", "-1"]);
+    run_comparison_tests_in_dir_except(
+        "simple",
+        &["-e", "tabular", "-l", "-c", "-1"],
+        CITE_FIXTURES,
+    );
 }
 
 #[test]
 fn test_complex_latex_files_srcloc() {
-    run_comparison_tests_in_dir(".", &["-e", "tabular", "-l", "-c", "-1"]);
+    run_comparison_tests_in_dir_except(".", &["-e", "tabular", "-l", "-c", "-1"], CITE_FIXTURES);
+}
+
+#[test]
+fn test_tex_files_math_flag() {
+    run_comparison_tests_in_dir("tex", &["-t", "-r"]);
+}
+
+#[test]
+fn test_tex_files_space_flag() {
+    run_comparison_tests_in_dir("tex", &["-t", "-s"]);
 }
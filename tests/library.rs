@@ -0,0 +1,1943 @@
+//! Tests of the library API that don't need opendetex for comparison.
+
+use std::fs;
+use std::path::PathBuf;
+
+use detex::{
+    Detex, DetexError, FileResolver, Options, SourceSpan, TildeOutput, detex_str, extract,
+    mode_from_program_name,
+};
+
+/// Create a fresh scratch directory for a test
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("detex-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_warnings_collected_for_missing_input() {
+    let mut output = Vec::new();
+    let mut detex = Detex::new(Options::default(), &mut output);
+    detex
+        .process_file("tests/latex/missing_input.tex")
+        .expect("processing should succeed");
+    assert_eq!(
+        detex.warnings(),
+        ["can't open \\input file does_not_exist".to_string()]
+    );
+}
+
+#[test]
+fn test_input_resolves_configured_extension() {
+    let dir = scratch_dir("input-extensions");
+    fs::write(dir.join("main.tex"), "before \\input{foo} after\n").unwrap();
+    fs::write(dir.join("foo.ltx"), "included").unwrap();
+
+    let opts = Options {
+        input_paths: vec![dir.to_string_lossy().into_owned()],
+        input_extensions: vec!["tex".to_string(), "ltx".to_string()],
+        ..Default::default()
+    };
+
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex.process_file("main.tex").unwrap();
+    assert!(detex.warnings().is_empty());
+    drop(detex);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "before included after\n"
+    );
+}
+
+#[test]
+fn test_input_braced_and_bare_names() {
+    let dir = scratch_dir("input-names");
+    fs::write(
+        dir.join("main.tex"),
+        "A \\input{chap1.tex} B \\input{ my chapter } C \\input chap1 D\n",
+    )
+    .unwrap();
+    fs::write(dir.join("chap1.tex"), "one").unwrap();
+    fs::write(dir.join("my chapter.tex"), "two").unwrap();
+
+    let opts = Options::default().with_input_paths(vec![dir.to_string_lossy().into_owned()]);
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex.process_file("main.tex").unwrap();
+    assert!(detex.warnings().is_empty());
+    drop(detex);
+    assert_eq!(String::from_utf8(output).unwrap(), "A one B two C one D\n");
+}
+
+#[test]
+fn test_import_joins_directory_and_file() {
+    let dir = scratch_dir("import");
+    fs::create_dir_all(dir.join("chapters/sec")).unwrap();
+    fs::write(
+        dir.join("main.tex"),
+        "\\documentclass{book}\nA \\import{chapters/}{one} B \\includefrom{chapters}{three.tex} C\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("chapters/one.tex"),
+        "one \\subimport*{sec/}{two} done",
+    )
+    .unwrap();
+    fs::write(dir.join("chapters/sec/two.tex"), "two").unwrap();
+    fs::write(dir.join("chapters/three.tex"), "three").unwrap();
+
+    let opts = Options::default().with_input_paths(vec![dir.to_string_lossy().into_owned()]);
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex.process_file("main.tex").unwrap();
+    assert!(detex.warnings().is_empty(), "{:?}", detex.warnings());
+    drop(detex);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "A one two done B three C\n"
+    );
+}
+
+#[test]
+fn test_file_stack_overflow_recorded_quietly() {
+    let dir = scratch_dir("file-stack-overflow");
+    for i in 0..300 {
+        fs::write(
+            dir.join(format!("f{}.tex", i)),
+            format!("x\\input{{f{}}}\n", i + 1),
+        )
+        .unwrap();
+    }
+
+    let opts = Options {
+        input_paths: vec![dir.to_string_lossy().into_owned()],
+        suppress_warnings: true,
+        ..Default::default()
+    };
+
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex.process_file("f0.tex").unwrap();
+    assert_eq!(
+        detex.warnings(),
+        ["file stack overflow, ignoring f256".to_string()]
+    );
+}
+
+#[test]
+fn test_include_cycle_detected() {
+    let dir = scratch_dir("include-cycle");
+    fs::write(dir.join("a.tex"), "A \\input{b} end A.\n").unwrap();
+    fs::write(dir.join("b.tex"), "B \\input{a.tex} end B.").unwrap();
+    fs::write(dir.join("self.tex"), "Self \\input{./self} end.\n").unwrap();
+
+    let opts = Options {
+        input_paths: vec![dir.to_string_lossy().into_owned()],
+        suppress_warnings: true,
+        ..Default::default()
+    };
+
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex.process_file("a").unwrap();
+    detex.process_file("self.tex").unwrap();
+    assert_eq!(
+        detex.warnings(),
+        [
+            "include cycle detected: a.tex".to_string(),
+            "include cycle detected: ./self".to_string(),
+        ]
+    );
+    drop(detex);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "A B  end B. end A.\nSelf  end.\n"
+    );
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_detex_files_in_order() {
+    let dir = scratch_dir("parallel");
+    let mut files = Vec::new();
+    for i in 0..20 {
+        let path = dir.join(format!("f{}.tex", i));
+        fs::write(&path, format!("File \\emph{{{}}}.\n", i)).unwrap();
+        files.push(path.to_string_lossy().into_owned());
+    }
+    files.push("no-such-file.tex".to_string());
+
+    let opts = Options {
+        jobs: 4,
+        ..Default::default()
+    };
+    let outputs = detex::detex_files(&files, &opts);
+    assert_eq!(outputs.len(), 21);
+    for (i, output) in outputs[..20].iter().enumerate() {
+        assert_eq!(output.text, format!("File {}.\n", i).into_bytes());
+        assert!(output.result.is_ok());
+    }
+    assert!(matches!(
+        outputs[20].result,
+        Err(DetexError::FileNotFound(_))
+    ));
+}
+
+#[cfg(not(feature = "parallel"))]
+#[test]
+fn test_jobs_needs_parallel_feature() {
+    let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        Options::from_args(&args(&["detex", "-j", "4", "a.tex"])).unwrap_err(),
+        "-j needs detex built with the parallel feature"
+    );
+    assert_eq!(
+        Options::from_args(&args(&["detex", "--jobs=4", "a.tex"])).unwrap_err(),
+        "--jobs needs detex built with the parallel feature"
+    );
+}
+
+#[test]
+fn test_state_reset_between_inputs() {
+    let opts = Options {
+        latex: true,
+        suppress_warnings: true,
+        ..Default::default()
+    }
+    .with_env_ignore("equation");
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    for (input, name) in [
+        ("A \\footnote{unclosed\n", "first"),
+        ("x} Second.\n", "second"),
+        ("\\begin{tabular}{ll}\na & b\n", "third"),
+        ("Q & A.\n", "fourth"),
+    ] {
+        detex
+            .process_reader(std::io::Cursor::new(input.as_bytes().to_vec()), name)
+            .unwrap();
+    }
+    drop(detex);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "A (unclosed\nx Second.\n\na   b\nQ & A.\n"
+    );
+}
+
+#[test]
+fn test_detect_encoding() {
+    let opts = Options {
+        detect_encoding: true,
+        ..Default::default()
+    };
+    let utf16le: Vec<u8> = [0xff, 0xfe]
+        .into_iter()
+        .chain(
+            "Caf\u{e9} \\emph{na\u{ef}ve}\n"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes),
+        )
+        .collect();
+    for (input, name) in [
+        (
+            b"\xef\xbb\xbfCaf\xc3\xa9 \\emph{na\xc3\xafve}\n".to_vec(),
+            "utf8-bom",
+        ),
+        (utf16le, "utf16le"),
+        (b"Caf\xe9 \\emph{na\xefve}\n".to_vec(), "latin1"),
+        ("Caf\u{e9} \\emph{na\u{ef}ve}\n".as_bytes().to_vec(), "utf8"),
+    ] {
+        let mut output = Vec::new();
+        let mut detex = Detex::new(opts.clone(), &mut output);
+        detex
+            .process_reader(std::io::Cursor::new(input), name)
+            .unwrap();
+        drop(detex);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Caf\u{e9} na\u{ef}ve\n",
+            "{}",
+            name
+        );
+    }
+}
+
+/// A resolver serving files from memory
+struct MemoryResolver(Vec<(&'static str, &'static str)>);
+
+impl FileResolver for MemoryResolver {
+    fn resolve(&self, name: &str, _opts: &Options) -> Option<(Box<dyn std::io::Read>, String)> {
+        let name = name.strip_suffix(".tex").unwrap_or(name);
+        let &(path, text) = self.0.iter().find(|(path, _)| *path == name)?;
+        Some((Box::new(text.as_bytes()), path.to_string()))
+    }
+}
+
+#[test]
+fn test_custom_file_resolver() {
+    let resolver = MemoryResolver(vec![
+        ("main", "Start \\input{intro} \\input{missing} end.\n"),
+        ("intro", "from \\emph{memory}"),
+    ]);
+
+    let mut output = Vec::new();
+    let mut detex = Detex::new(Options::default(), &mut output).with_resolver(resolver);
+    detex.process_file("main.tex").unwrap();
+    assert_eq!(
+        detex.warnings(),
+        ["can't open \\input file missing".to_string()]
+    );
+    assert!(matches!(
+        detex.process_file("other"),
+        Err(DetexError::FileNotFound(_))
+    ));
+    drop(detex);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "Start from memory  end.\n"
+    );
+}
+
+/// A reader that returns at most one byte per read, to split multi-byte
+/// characters and lookahead across buffer refills.
+struct OneByteReader<'a>(&'a [u8]);
+
+impl std::io::Read for OneByteReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(1);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+#[test]
+fn test_process_reader_across_chunk_boundaries() {
+    // Borrowed rather than 'static input
+    let input =
+        String::from("Caf\u{e9} na\u{ef}ve \\cite{x} text % comment\nline two $x = y$ done\n");
+    let opts = Options {
+        latex: true,
+        src_loc: true,
+        ..Default::default()
+    };
+
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts.clone(), &mut output);
+    detex.process_reader(input.as_bytes(), "whole").unwrap();
+    drop(detex);
+
+    let mut streamed = Vec::new();
+    let mut detex = Detex::new(opts, &mut streamed);
+    detex
+        .process_reader(OneByteReader(input.as_bytes()), "whole")
+        .unwrap();
+    drop(detex);
+
+    assert_eq!(
+        String::from_utf8(output.clone()).unwrap(),
+        "whole:1: Caf\u{e9} na\u{ef}ve text \nwhole:2: line two  done\n"
+    );
+    assert_eq!(streamed, output);
+}
+
+#[test]
+fn test_process_reader_rejects_invalid_utf8() {
+    let mut output = Vec::new();
+    let mut detex = Detex::new(Options::default(), &mut output);
+    let err = detex
+        .process_reader(std::io::Cursor::new(b"ok \xff".to_vec()), "bad")
+        .unwrap_err();
+    assert!(matches!(err, DetexError::Io { ref path, .. } if path == "bad"));
+    assert_eq!(
+        err.to_string(),
+        "error reading bad: stream did not contain valid UTF-8"
+    );
+}
+
+#[test]
+fn test_detex_str_skips_includes() {
+    let text = detex_str(
+        "\\documentclass{article}\n\\begin{document}\nHello \\input{chapter} world.\n\\end{document}\n",
+        Options::default(),
+    )
+    .unwrap();
+    assert_eq!(text, "Hello  world.\n");
+}
+
+#[test]
+fn test_options_builders() {
+    let opts = Options::default()
+        .with_latex(true)
+        .with_cite(true)
+        .with_replace(true)
+        .with_space(true)
+        .with_no_follow(true)
+        .with_src_loc(true)
+        .with_word(true)
+        .with_input_paths(vec!["chapters".to_string()]);
+    assert!(opts.latex && opts.cite && opts.replace && opts.space);
+    assert!(opts.no_follow && opts.src_loc && opts.word);
+    assert_eq!(opts.input_paths, ["chapters"]);
+
+    let text = detex_str(
+        "See \\ref{fig} and $x$.\n",
+        Options::default()
+            .with_latex(true)
+            .with_cite(true)
+            .with_replace(true),
+    )
+    .unwrap();
+    assert_eq!(text, "See fig and noun.\n");
+}
+
+#[test]
+fn test_options_from_args() {
+    let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    let (opts, files) = Options::from_args(&args(&[
+        "detex",
+        "-cw",
+        "-e",
+        "equation",
+        "--lines=2:4",
+        "a.tex",
+        "--",
+        "-b.tex",
+    ]))
+    .unwrap();
+    assert!(opts.cite && opts.word && !opts.latex);
+    assert_eq!(opts.env_ignore, ["equation"]);
+    assert_eq!(opts.line_range, Some((2, 4)));
+    assert_eq!(files, ["a.tex", "-b.tex"]);
+
+    let (opts, files) = Options::from_args(&args(&["/usr/bin/delatex", "-o", "out.txt"])).unwrap();
+    assert!(opts.latex);
+    assert_eq!(opts.output_path.as_deref(), Some("out.txt"));
+    assert!(files.is_empty());
+
+    let (opts, _) = Options::from_args(&args(&["detex", "-x", "-v"])).unwrap();
+    assert_eq!(opts.unknown_option.as_deref(), Some("-x"));
+    assert!(!opts.show_version);
+
+    assert_eq!(
+        Options::from_args(&args(&["detex", "--max-line-length", "wide"])).unwrap_err(),
+        "--max-line-length option requires a width"
+    );
+}
+
+#[test]
+fn test_extract_metadata() {
+    let input = "\\documentclass{article}\n\\title{A \\emph{Study}}\n\\author{Ann Lee \\and Bo Chen\\thanks{MIT}}\n\
+                 \\begin{document}\n\\maketitle\n\\section{Intro}\\label{sec:intro}\n\
+                 As in \\cite[p.~2]{knuth84, lamport94}.\n\\subsection*{More \\texttt{Text}}\n\
+                 See \\ref{sec:intro} and \\cite{knuth84}.\n\\end{document}\n";
+    let result = extract(input, Options::default());
+    assert_eq!(result.text, detex_str(input, Options::default()).unwrap());
+    assert_eq!(result.title.as_deref(), Some("A Study"));
+    assert_eq!(result.authors, ["Ann Lee", "Bo Chen"]);
+    assert_eq!(
+        result.sections,
+        [(2, "Intro".to_string()), (3, "More Text".to_string())]
+    );
+    assert_eq!(result.labels, ["sec:intro"]);
+    assert_eq!(result.citations, ["knuth84", "lamport94", "knuth84"]);
+    assert_eq!(result.date, None);
+    assert_eq!(result.abstract_text, None);
+    assert_eq!(result.word_count, 14);
+
+    let input = "\\section{Two\nLines}\nBy \\citep{a} and \\parencite[p.~1]{b, c}.\n\
+                 \\begin{equation}\nx = 1 \\label{eq:1}\n\\end{equation}\nEnd.\n";
+    let opts = Options::default().with_latex(true).with_src_loc(true);
+    let result = extract(input, opts.clone());
+    assert_eq!(result.text, detex_str(input, opts).unwrap());
+    assert!(result.text.starts_with("<string>:1: Two\n"));
+    assert_eq!(result.sections, [(2, "Two Lines".to_string())]);
+    assert_eq!(result.labels, ["eq:1"]);
+    assert_eq!(result.citations, ["a", "b", "c"]);
+}
+
+#[test]
+fn test_replace_with_custom_word() {
+    let opts = Options {
+        latex: true,
+        replace: true,
+        replace_with: Some("EQUATION".to_string()),
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Since $x = y$ and \\(a \\leq b\\), we get\n\\[ x \\in S \\]\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "Since EQUATION and EQUATION, we get\nEQUATION\n");
+}
+
+#[test]
+fn test_math_decorators_give_one_noun() {
+    let opts = Options {
+        latex: true,
+        replace: true,
+        ..Default::default()
+    };
+    let text = detex_str("Box $\\boxed{x}$.\n", opts.clone()).unwrap();
+    assert_eq!(text, "Box noun.\n");
+
+    let text = detex_str(
+        "Sum $\\underbrace{a + b}_{n}$ and $\\overset{!}{=} y$ and \\[ \\boxed{x = y} \\]\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "Sum noun and noun verbs noun and noun verbs noun\n");
+}
+
+#[test]
+fn test_replace_grammar() {
+    let opts = Options {
+        latex: true,
+        replace: true,
+        replace_grammar: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Let $x$ be, so $x = y$ and $a + b$ or \\(\\frac{1}{2}\\) and\n\\[ \\sum_i a_i \\leq 1 \\]\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(
+        text,
+        "Let it be, so noun equals noun and the expression or the expression and\nnoun equals noun\n"
+    );
+
+    // \int and \infty start like \in, but only the exact name is a relation
+    let text = detex_str(
+        "Then $\\int f$ is $\\infty$ and $x \\in S$.\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "Then the expression is it and noun equals noun.\n");
+
+    let text = detex_str(
+        "Let $x$ be $a + b$.\n",
+        Options {
+            replace_grammar: false,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "Let noun be noun.\n");
+}
+
+#[test]
+fn test_ensuremath_in_text_and_math() {
+    let opts = Options {
+        latex: true,
+        replace: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Text \\ensuremath{x = y} and $\\ensuremath{x} + 1$ and \\[\\ensuremath{\\alpha} \\leq 1\\]\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "Text noun verbs noun and noun and noun verbs noun\n");
+
+    let text = detex_str(
+        "Text \\ensuremath{\\alpha} here.\n",
+        Options {
+            replace: false,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "Text  here.\n");
+}
+
+#[test]
+fn test_break_hints_dropped() {
+    let opts = Options {
+        latex: true,
+        space: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "\\penalty100 text and \\penalty -50 more, long\\allowbreak word\\nobreak\\ end\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "text and more, longword end\n");
+}
+
+#[test]
+fn test_unterminated_verb_error() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let err = detex_str("first line\n\\verb|never closed\n", opts).unwrap_err();
+    assert!(matches!(err, DetexError::UnterminatedVerb { line: 2 }));
+    assert_eq!(err.to_string(), "\\verb not complete before eof (line 2)");
+}
+
+#[test]
+fn test_intertext_survives_ignored_align() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Consider\n\\begin{align}\na &= b \\\\\n\\intertext{which means that}\nc &= d\n\\end{align}\nholds.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "Consider\nwhich means that\nholds.\n");
+}
+
+#[test]
+fn test_shortintertext_survives_ignored_align() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Consider\n\\begin{align*}\na &= b \\\\\n\\shortintertext{and so}\nc &= d\n\\end{align*}\nholds.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "Consider\nand so\nholds.\n");
+}
+
+#[test]
+fn test_word_mode_keeps_non_ascii_letters() {
+    let opts = Options {
+        word: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Une id\u{e9}e na\u{ef}ve, l'\u{e9}t\u{e9} \u{e0} Stra\u{df}e.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(
+        text,
+        "Une\nid\u{e9}e\nna\u{ef}ve\nl'\u{e9}t\u{e9}\n\u{e0}\nStra\u{df}e\n"
+    );
+}
+
+#[test]
+fn test_brace_level_after_ignored_environment() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "A\\footnote{See \\begin{equation}{x}\\end{equation} here} after.\n\
+         \\begin{equation}y\\end{equation}B\\footnote{again} done.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "A(See  here) after.\nB(again) done.\n");
+}
+
+#[test]
+fn test_font_commands_keep_text() {
+    let text = detex_str(
+        "A \\textbf{bold}, \\emph{emphasized} and \\texttt{\\%typed} word; \\textbf{\\emph{x}}.\n",
+        Options::default(),
+    )
+    .unwrap();
+    assert_eq!(text, "A bold, emphasized and %typed word; x.\n");
+}
+
+#[test]
+fn test_href_keeps_link_text() {
+    let text = detex_str(
+        "See \\href{https://example.com/a%20b#top}{the site}. Also\\footnote{at \\href{x}{here}} done.\n",
+        Options::default(),
+    )
+    .unwrap();
+    assert_eq!(text, "See the site. Also(at here) done.\n");
+}
+
+#[test]
+fn test_json_lines() {
+    let opts = Options {
+        json_lines: true,
+        ..Default::default()
+    };
+    let text = detex_str("Hi \\textbf{there} \"q\"\n\\emph{x}y\ttab\n", opts).unwrap();
+    let tokens: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("each line is JSON"))
+        .collect();
+    let token = |text: &str, line: u64, col: u64| serde_json::json!({"text": text, "file": "<string>", "line": line, "col": col});
+    assert_eq!(
+        tokens,
+        [
+            token("Hi", 1, 1),
+            token("there", 1, 12),
+            token("\"q\"", 1, 19),
+            token("xy", 2, 7),
+            token("tab", 2, 11),
+        ]
+    );
+}
+
+#[test]
+fn test_url_echoed_verbatim() {
+    let text = detex_str(
+        "Visit \\url{https://example.com/~me/a_b%20c} or \\nolinkurl{x\\y}.\n",
+        Options::default(),
+    )
+    .unwrap();
+    assert_eq!(text, "Visit https://example.com/~me/a_b%20c or x\\y.\n");
+}
+
+#[test]
+fn test_unclosed_ignored_environment_warns() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex
+        .process_reader(
+            std::io::Cursor::new(b"Before\n\\begin{equation}\nx = {y\n".to_vec()),
+            "truncated",
+        )
+        .unwrap();
+    assert_eq!(
+        detex.warnings(),
+        ["environment 'equation' not closed before eof".to_string()]
+    );
+    drop(detex);
+    assert_eq!(String::from_utf8(output).unwrap(), "Before\n");
+}
+
+#[test]
+fn test_accents_become_unicode() {
+    let text = detex_str(
+        "M\\\"uller, \\'{e}t\\'e, \\`a, na\\\"{\\i}ve, \\^\\i le, Espa\\~na, gar\\c{c}on, \\v Cech, \\'x.\n",
+        Options::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        text,
+        "M\u{fc}ller, \u{e9}t\u{e9}, \u{e0}, na\u{ef}ve, \u{ee}le, Espa\u{f1}a, gar\u{e7}on, \u{10c}ech, x\u{301}.\n"
+    );
+
+    let opts = Options {
+        word: true,
+        ..Default::default()
+    };
+    let words = detex_str("Herr M\\\"uller und G\\\"{o}del\n", opts).unwrap();
+    assert_eq!(words, "Herr\nM\u{fc}ller\nund\nG\u{f6}del\n");
+}
+
+#[test]
+fn test_nested_ignored_environment_of_same_name() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Before\n\\begin{tabular}{ll}\nouter & \\begin{tabular}{c} inner \\end{tabular} \\\\\nstill outer\n\\end{tabular}\nAfter\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "Before\n\nAfter\n");
+}
+
+#[test]
+fn test_item_starts_new_line() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "\\begin{itemize}\n\\item apple \\item banana\n\\item[Note] cherry\n\\end{itemize}\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "\napple \nbanana\nNote: cherry\n");
+
+    let text = detex_str("\\item[{[a]}] text] more\n", opts.clone()).unwrap();
+    assert_eq!(text, "[a]: text] more\n");
+
+    let words = detex_str("\\item apple \\item banana", Options { word: true, ..opts }).unwrap();
+    assert_eq!(words, "apple\nbanana\n");
+}
+
+#[test]
+fn test_custom_noun_text() {
+    let opts = Options {
+        latex: true,
+        replace: true,
+        ..Default::default()
+    }
+    .with_noun_text("Nomen")
+    .with_verb_noun_text(" ist Nomen");
+    let text = detex_str("Sei $x \\in S$ und $y$.\n", opts).unwrap();
+    assert_eq!(text, "Sei Nomen ist Nomen und Nomen.\n");
+}
+
+#[test]
+fn test_squeeze_collapses_spaces_under_space_flag() {
+    let input = "A \\foo \\bar{x} $y$ B\n";
+    let opts = Options {
+        space: true,
+        ..Default::default()
+    };
+    let spaced = detex_str(input, opts.clone()).unwrap();
+    assert!(spaced.contains("  "));
+
+    let squeezed = detex_str(
+        input,
+        Options {
+            squeeze: true,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(squeezed, "A x B\n");
+}
+
+#[test]
+fn test_squeeze_keeps_verbatim_spaces() {
+    let opts = Options {
+        latex: true,
+        space: true,
+        squeeze: true,
+        ..Default::default()
+    }
+    .with_env_ignore("");
+    let text = detex_str(
+        "A \\foo  B \\begin{verbatim}a    b\\end{verbatim} \\verb|c   d|.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "A B a    b c   d.\n");
+}
+
+#[test]
+fn test_declare_math_operator_killed() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "\\DeclareMathOperator{\\rank}{rank}\n\\DeclareMathOperator*{\\argmax}{arg\\,max}\nThe rank $\\rank A = \\argmax_x f$ is small.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "The rank  is small.\n");
+}
+
+#[test]
+fn test_starred_environment_ignored() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "A\n\\begin{align*}\nx &= y \\\\\n\\begin{align*}z\\end{align*}\n\\end{align*}\nB \\begin{equation*}z\\end{equation*} C\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "A\n\nB  C\n");
+}
+
+#[test]
+fn test_environment_names_with_digits() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    }
+    .with_env_ignore("align2,tabular");
+    let text = detex_str(
+        "A \\begin{align2}x\\end{align2} B \\begin{tabular*}{5cm}{ll}a & b\\end{tabular*} C \\begin{quote2}q\\end{quote2}.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "A  B  C q.\n");
+}
+
+#[test]
+fn test_line_range_selects_source_lines() {
+    let mut input = String::new();
+    for line in 1..=30 {
+        match line {
+            8 => input.push_str("\\begin{equation}\n"),
+            11 => input.push_str("\\end{equation} after equation\n"),
+            _ => input.push_str(&format!("line {} \\emph{{text}}\n", line)),
+        }
+    }
+    let dir = scratch_dir("line-range");
+    let path = dir.join("long.tex");
+    fs::write(&path, input).unwrap();
+
+    let opts = Options {
+        latex: true,
+        line_range: Some((10, 20)),
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex.process_file(path.to_str().unwrap()).unwrap();
+    drop(detex);
+
+    let mut expected = " after equation\n".to_string();
+    for line in 12..=20 {
+        expected.push_str(&format!("line {} text\n", line));
+    }
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+}
+
+#[test]
+fn test_line_numbers_after_multiline_inline_math() {
+    let opts = Options {
+        latex: true,
+        src_loc: true,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex
+        .process_reader(
+            std::io::Cursor::new(
+                b"one $a \\text{spans\ntwo\nlines} b$ c\nfour \\(x\ny\\) z\nsix\n".to_vec(),
+            ),
+            "math",
+        )
+        .unwrap();
+    drop(detex);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "math:1: one  c\nmath:4: four \nmath:5:  z\nmath:6: six\n"
+    );
+}
+
+#[test]
+fn test_starred_verb_shows_spaces() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str("\\verb|a b| and \\verb*|a b|.\n", opts.clone()).unwrap();
+    assert_eq!(text, "a b and a\u{2423}b.\n");
+
+    let text = detex_str(
+        "\\verb*+x y+\n",
+        Options {
+            visible_space: '_',
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "x_y\n");
+}
+
+#[test]
+fn test_custom_verb_commands() {
+    let input = "Call \\code|f(x){}| or \\code*+a b+ now.\n";
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(input, opts.clone().with_verb_command("code")).unwrap();
+    assert_eq!(text, "Call f(x){} or a\u{2423}b now.\n");
+
+    // Unregistered, \code is an unknown command and its argument is text
+    let text = detex_str(input, opts).unwrap();
+    assert_eq!(text, "Call f(x) or *+a b+ now.\n");
+}
+
+#[test]
+fn test_wrap_at_word_boundaries() {
+    let opts = Options {
+        latex: true,
+        wrap_at: Some(20),
+        ..Default::default()
+    };
+    let input = "The quick brown \\emph{fox} jumps over the lazy dog and keeps on running far away.\n\nSecond paragraph here.\n";
+    let text = detex_str(input, opts.clone()).unwrap();
+    for line in text.lines() {
+        assert!(line.chars().count() <= 20, "line too long: {:?}", line);
+    }
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let unwrapped = detex_str(
+        input,
+        Options {
+            wrap_at: None,
+            ..opts.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(words, unwrapped.split_whitespace().collect::<Vec<_>>());
+    assert!(text.contains("\n\nSecond paragraph"));
+
+    let text = detex_str("Code: \\verb|a very long verbatim string| end\n", opts).unwrap();
+    assert_eq!(text, "Code: a very long verbatim string\nend\n");
+}
+
+#[test]
+fn test_code_listings_echoed_verbatim() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    }
+    .with_env_ignore("equation");
+    let text = detex_str(
+        "A\n\\begin{lstlisting}[language=C]\nif (x) { y(\"\\\\n\"); }\n\\end{verbatim}\n\\end{lstlisting}\n\
+         B\n\\begin{minted}[linenos]{python}\ndef f(): return {1: 2}\n\\end{minted}\n\
+         C\n\\begin{Verbatim}\n\\textbf{raw}\n\\end{Verbatim}\nD \\begin{verbatim}\\x{\\end { verbatim }\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(
+        text,
+        "A\n\nif (x) { y(\"\\\\n\"); }\n\\end{verbatim}\n\nB\n\ndef f(): return {1: 2}\n\n\
+         C\n\n\\textbf{raw}\n\nD \\x{\n"
+    );
+}
+
+#[test]
+fn test_verbatim_trailing_backslash() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    }
+    .with_env_ignore("equation");
+    let text = detex_str(
+        "Path:\n\\begin{verbatim}\nC:\\dir\\\nnext line\\\n\\end{verbatim}\nafter\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "Path:\n\nC:\\dir\\\nnext line\\\n\nafter\n");
+}
+
+#[test]
+fn test_assignment_operators_are_verbs() {
+    let opts = Options {
+        latex: true,
+        replace: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Let $x \\coloneqq y$, $f \\eqqcolon g$ and $a \\gets b$; $c \\cdot d$.\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(
+        text,
+        "Let noun verbs noun, noun verbs noun and noun verbs noun; noun.\n"
+    );
+
+    // Only the exact names count, unlike the prefixes of detex.l's list
+    let text = detex_str("Take $\\getsize{x}$ or $x \\leqslant y$.\n", opts).unwrap();
+    assert_eq!(text, "Take noun or noun verbs noun.\n");
+}
+
+#[test]
+fn test_comment_environment_dropped() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    }
+    .with_env_ignore("");
+    let text = detex_str(
+        "Kept.\n\\begin{comment}\nDraft with } unbalanced { braces $x\n\\end{document}\n\\end{comment}\nAlso kept.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "Kept.\n\nAlso kept.\n");
+}
+
+#[test]
+fn test_cite_optional_notes_dropped() {
+    let input = "See \\cite[see][p.~3]{key}, Fig.~\\ref[x]{fig}.\n";
+    let opts = Options::default().with_latex(true);
+    let text = detex_str(input, opts.clone()).unwrap();
+    assert_eq!(text, "See, Fig. .\n");
+
+    // With -c the keys are echoed without the notes
+    let text = detex_str(input, opts.with_cite(true)).unwrap();
+    assert_eq!(text, "See key, Fig. fig.\n");
+
+    // In TeX mode nothing is killed, so the notes stay as in detex.l
+    let opts = Options::default().with_cite(true);
+    let text = detex_str(input, opts).unwrap();
+    assert_eq!(text, "See[see][p. 3]key, Fig. [x]fig.\n");
+}
+
+#[cfg(feature = "test-support")]
+#[test]
+fn test_well_formed_document_ends_in_normal() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex
+        .process_file("tests/latex/simple/09_nested_environments.tex")
+        .unwrap();
+    assert_eq!(detex.current_state_name(), "Normal");
+}
+
+#[test]
+fn test_verb_space_delimiter() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    // As in opendetex, a space is a valid delimiter
+    let text = detex_str("A \\verb x and \\verb |x| end\n", opts.clone()).unwrap();
+    assert_eq!(text, "A xand |x|end\n");
+
+    let err = detex_str("A \\verb |x|\n", opts).unwrap_err();
+    assert!(matches!(err, DetexError::UnterminatedVerb { line: 1 }));
+}
+
+#[test]
+fn test_cleveref_like_ref() {
+    let input = "See \\cref{a,b,c}, \\Cref*{fig} and \\autoref{sec}.\n";
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(input, opts.clone()).unwrap();
+    assert_eq!(text, "See ,  and .\n");
+
+    let text = detex_str(input, Options { cite: true, ..opts }).unwrap();
+    assert_eq!(text, "See a,b,c, fig and sec.\n");
+}
+
+#[test]
+fn test_eqref_like_ref() {
+    let input = "By \\eqref{eq:foo} we are done.\n";
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(input, opts.clone()).unwrap();
+    assert_eq!(text, "By  we are done.\n");
+
+    let text = detex_str(input, Options { cite: true, ..opts }).unwrap();
+    assert_eq!(text, "By eq:foo we are done.\n");
+}
+
+#[test]
+fn test_rtl_text_passes_through() {
+    let arabic =
+        "\u{645}\u{631}\u{62d}\u{628}\u{627} \u{628}\u{627}\u{644}\u{639}\u{627}\u{644}\u{645}";
+    let hebrew = "\u{5e9}\u{5dc}\u{5d5}\u{5dd}";
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        &format!(
+            "Greeting: \\textarabic{{{}}} and \\texthebrew{{{}}}.\n\\begin{{RTL}}\n{}\n\\end{{RTL}}\n",
+            arabic, hebrew, arabic
+        ),
+        opts,
+    )
+    .unwrap();
+    assert_eq!(
+        text,
+        format!("Greeting: {} and {}.\n\n{}\n", arabic, hebrew, arabic)
+    );
+}
+
+#[test]
+fn test_expand_parameterless_macros() {
+    let input = "\\newcommand{\\tool}{detex}\n\\newcommand\\Tool{The \\tool{} program}\n\
+                 \\providecommand{\\tool}{ignored}\n\\newcommand{\\twice}[1]{#1 #1}\n\
+                 \\newcommand{\\loop}{\\loop}\n\
+                 \\Tool strips \\tool\\ markup; \\twice{x} stays.\n";
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str("\\newcommand{\\tool}{detex}\nUse \\tool.\n", opts.clone()).unwrap();
+    assert_eq!(text, "Use .\n");
+
+    let mut output = Vec::new();
+    let mut detex = Detex::new(
+        Options {
+            expand_macros: true,
+            suppress_warnings: true,
+            ..opts
+        },
+        &mut output,
+    );
+    detex
+        .process_reader(
+            std::io::Cursor::new(format!("{}\\loop done\n", input).into_bytes()),
+            "macros",
+        )
+        .unwrap();
+    assert_eq!(detex.warnings().len(), 1);
+    drop(detex);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\n\n\n\n\nThe detex program strips detex markup; x stays.\ndone\n"
+    );
+}
+
+#[test]
+fn test_skip_def_bodies() {
+    let input = "\\def\\name{Section}\n\\def\\pair#1#2{(#1, {#2})}\n\\def\\{{brace}Text.\n";
+    let text = detex_str(input, Options::default()).unwrap();
+    assert_eq!(text, "Section\n(#1, #2)\nbraceText.\n");
+
+    let opts = Options {
+        skip_def_bodies: true,
+        ..Default::default()
+    };
+    let text = detex_str(input, opts).unwrap();
+    assert_eq!(text, "\n\nText.\n");
+}
+
+#[test]
+fn test_cleveref_with_cite_flag() {
+    let input = "See \\cref{a,b}, \\Cref{c}, \\cpageref{d} and \\crefrange{e}{f}.\n";
+    let opts = Options {
+        latex: true,
+        cite: true,
+        ..Default::default()
+    };
+    let text = detex_str(input, opts.clone()).unwrap();
+    assert_eq!(text, "See a,b, c, d and e,f.\n");
+
+    let text = detex_str(
+        input,
+        Options {
+            cite: false,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "See , ,  and .\n");
+}
+
+#[test]
+fn test_mode_from_program_name() {
+    assert!(mode_from_program_name("delatex").latex);
+    assert!(mode_from_program_name("delatex.exe").latex);
+    assert!(!mode_from_program_name("detex").latex);
+
+    let mut opts = Options::default();
+    mode_from_program_name("delatex").apply(&mut opts);
+    assert!(opts.latex);
+}
+
+#[test]
+fn test_unicode_dashes() {
+    let input = "Pages 3--5 --- a well-known fact.\n";
+    let text = detex_str(input, Options::default()).unwrap();
+    assert_eq!(text, "Pages 3-5 - a well-known fact.\n");
+
+    let opts = Options {
+        unicode_punct: true,
+        ..Default::default()
+    };
+    let text = detex_str(input, opts).unwrap();
+    assert_eq!(text, "Pages 3\u{2013}5 \u{2014} a well-known fact.\n");
+}
+
+#[test]
+fn test_unicode_quotes() {
+    let input = "``Don't,'' she said, `fine' and ,,gut''.\n";
+    let text = detex_str(input, Options::default()).unwrap();
+    assert_eq!(text, "\"Don't,\" she said, 'fine' and \"gut\".\n");
+
+    let opts = Options {
+        unicode_punct: true,
+        ..Default::default()
+    };
+    let text = detex_str(input, opts).unwrap();
+    assert_eq!(
+        text,
+        "\u{201c}Don't,\u{201d} she said, \u{2018}fine\u{2019} and \u{201e}gut\u{201d}.\n"
+    );
+}
+
+#[test]
+fn test_math_tag_dropped() {
+    let opts = Options {
+        latex: true,
+        replace: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "So \\[ a \\leq b \\tag*{$x=y$} \\] and $c \\tag{c>0}$.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "So noun verbs noun and noun.\n");
+}
+
+#[test]
+fn test_math_phantom_dropped() {
+    let text = detex_str(
+        "$a\\phantom{=}b$ and $x \\hphantom{\\leq} y \\vphantom{<}$ but $a = b$.\n",
+        Options::default().with_replace(true),
+    )
+    .unwrap();
+    assert_eq!(text, "noun and noun but noun verbs noun.\n");
+}
+
+#[test]
+fn test_strict_latex_warns_about_plain_tex() {
+    let opts = Options {
+        latex: true,
+        no_follow: true,
+        strict_latex: true,
+        suppress_warnings: true,
+        ..Default::default()
+    };
+    let input = "\\def\\x{y}\nText $$a=b$$ and $c$.\n\\input{ok} \\input bare\n";
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts.clone(), &mut output);
+    detex
+        .process_reader(std::io::Cursor::new(input.as_bytes().to_vec()), "doc.tex")
+        .unwrap();
+    assert_eq!(
+        detex.warnings(),
+        [
+            "doc.tex:1: plain TeX \\def in LaTeX".to_string(),
+            "doc.tex:2: plain TeX $$ in LaTeX".to_string(),
+            "doc.tex:3: plain TeX \\input without braces in LaTeX".to_string(),
+        ]
+    );
+    drop(detex);
+
+    let plain = detex_str(
+        input,
+        Options {
+            strict_latex: false,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), plain);
+}
+
+#[test]
+fn test_ellipsis_commands() {
+    let input = "Wait\\ldots and \\dots{} then \\emph{etc\\textellipsis}\n$1, \\cdots, n$ end\n";
+    let text = detex_str(input, Options::default()).unwrap();
+    assert_eq!(text, "Wait...and ... then etc...\n end\n");
+
+    let opts = Options {
+        unicode_punct: true,
+        ..Default::default()
+    };
+    let text = detex_str("Wait\\ldots\n", opts).unwrap();
+    assert_eq!(text, "Wait\u{2026}\n");
+}
+
+#[test]
+fn test_par_breaks_paragraphs() {
+    let input = "First paragraph.\\par Second one.\n";
+    let text = detex_str(input, Options::default()).unwrap();
+    assert_eq!(text, "First paragraph.\n\nSecond one.\n");
+
+    let opts = Options {
+        word: true,
+        ..Default::default()
+    };
+    let words = detex_str("one\\par two\n", opts).unwrap();
+    assert_eq!(words, "one\ntwo\n");
+}
+
+#[test]
+fn test_synthetic_line_breaks_keep_line_numbers() {
+    let opts = Options {
+        latex: true,
+        src_loc: true,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex
+        .process_reader(
+            std::io::Cursor::new(b"One\\par Two \\item three\nfour\n".to_vec()),
+            "breaks",
+        )
+        .unwrap();
+    drop(detex);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "breaks:1: One\nbreaks:1: \nbreaks:1: Two \nbreaks:1: three\nbreaks:2: four\n"
+    );
+}
+
+#[test]
+fn test_reference_keys_echoed_verbatim() {
+    let opts = Options {
+        latex: true,
+        cite: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "A\\label{fig:my-figure_1} B \\ref{fig:my--figure_1} C \\cref{a:b-c_d,e--f} D \\eqref{eq:x~y}.\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "A B fig:my--figure_1 C a:b-c_d,e--f D eq:x~y.\n");
+
+    let text = detex_str(
+        "A\\label{fig:my-figure_1} B\n",
+        Options {
+            cite: false,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "A B\n");
+}
+
+#[test]
+fn test_tabular_row_breaks() {
+    let opts = Options {
+        latex: true,
+        env_ignore: vec!["equation".to_string()],
+        ..Default::default()
+    };
+    let text = detex_str(
+        "\\begin{tabular}{ll}\na & b \\\\ c & d \\\\[2ex] e\\\\\nf \\\\ \\hline\n\\end{tabular}\nOne\\\\\nTwo\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "\na   b \nc   d \ne\nf \nOne\n\nTwo\n");
+}
+
+#[test]
+fn test_table_column_spec_dropped() {
+    let opts = Options {
+        latex: true,
+        env_ignore: vec!["equation".to_string()],
+        ..Default::default()
+    };
+    let text = detex_str(
+        "\\begin{tabular}[t]{|l|c|r|}A\\end{tabular} \
+         \\begin{tabular*}{\\textwidth}{@{}ll@{}}B\\end{tabular*} \
+         \\begin{tabularx}{0.5\\linewidth}{lX}C\\end{tabularx} \
+         \\begin{array}{cc}D\\end{array} \
+         \\begin{longtable}{p{3cm}l}E\\end{longtable}\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "A B C D E");
+
+    let text = detex_str(
+        "x \\begin{tabular}{ll}a & b\\end{tabular} y\n",
+        Options {
+            env_ignore: vec!["tabular".to_string()],
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "x  y\n");
+}
+
+#[test]
+fn test_chemistry_formulas_as_math() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let input = "Add \\ce{H2SO4} to \\ch{H2O}.\n";
+    let text = detex_str(input, opts.clone()).unwrap();
+    assert_eq!(text, "Add  to .\n");
+
+    let text = detex_str(
+        input,
+        Options {
+            space: true,
+            ..opts.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "Add   to  .\n");
+
+    let text = detex_str(
+        input,
+        Options {
+            replace: true,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "Add noun to noun.\n");
+}
+
+#[test]
+fn test_multicolumn_keeps_content() {
+    let opts = Options {
+        latex: true,
+        env_ignore: vec!["equation".to_string()],
+        ..Default::default()
+    };
+    let text = detex_str(
+        "\\begin{tabular}{lll}\n\\multicolumn{2}{c|}{Header} & \\multirow{2}{*}{Tall \\emph{cell}} \\\\\n\\end{tabular}\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "\nHeader   Tall cell \n");
+
+    let text = detex_str("A \\raisebox{1ex}{up} B\n", opts).unwrap();
+    assert_eq!(text, "A up B\n");
+}
+
+#[test]
+fn test_stripped_args_keep_nested_braces() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "X \\raisebox{2pt}{a {nested} b} Y \\raisebox{1pt}[2pt]{c {d {e}} f} Z\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "X a nested b Y c d e f Z\n");
+}
+
+#[test]
+fn test_table_cells_separated_by_spaces() {
+    let opts = Options {
+        latex: true,
+        env_ignore: vec!["equation".to_string()],
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Q&A \\begin{tabular}{ccc}a&b&c\\end{tabular} R&D\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "Q&A a b c R&D\n");
+
+    let text = detex_str(
+        "\\begin{tabular}{ccc}a&b&c\\end{tabular}\n",
+        Options { word: true, ..opts },
+    )
+    .unwrap();
+    assert_eq!(text, "a\nb\nc\n");
+}
+
+#[test]
+fn test_keep_math_text() {
+    let opts = Options {
+        latex: true,
+        keep_math_text: true,
+        ..Default::default()
+    };
+    let text = detex_str("So $a = \\text{mass}$ here.\n", opts.clone()).unwrap();
+    assert_eq!(text, "So mass here.\n");
+
+    let text = detex_str(
+        "\\begin{align*}\nv &= \\mbox{speed} \\\\\n\\end{align*}\n\\begin{tikzpicture}\\node{\\mbox{box}};\\end{tikzpicture}\n\\(x \\text{if $y$}\\) \\[z \\text{else}\\]\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "speed\n\nif  else\n");
+
+    let text = detex_str(
+        "So $a = \\text{mass}$ here.\n",
+        Options {
+            keep_math_text: false,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "So  here.\n");
+}
+
+#[test]
+fn test_keep_math_text_operator_names() {
+    let opts = Options {
+        latex: true,
+        keep_math_text: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Take $\\operatorname{argmax}_x f(x)$ or \\(\\operatorname*{arg\\,min}_y g\\).\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "Take argmax or argmin.\n");
+
+    let text = detex_str(
+        "Take $\\operatorname{argmax}_x f(x)$.\n",
+        Options {
+            keep_math_text: false,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "Take .\n");
+}
+
+#[test]
+fn test_table_rules_dropped() {
+    let opts = Options {
+        latex: true,
+        env_ignore: vec!["equation".to_string()],
+        ..Default::default()
+    };
+    let input = "\\begin{tabular}{ll}\n\\toprule\na & b \\\\ \\midrule\n\
+                 \\cmidrule(lr){1-2} \\cline{1-2}\nc & d \\\\\n\\addlinespace[2pt]\n\
+                 \\hline\n\\bottomrule[1pt]\n\\end{tabular}\n";
+    let text = detex_str(input, opts.clone()).unwrap();
+    assert_eq!(text, "\na   b \nc   d \n");
+
+    let text = detex_str(
+        input,
+        Options {
+            space: true,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "  \na   b \nc   d \n ");
+}
+
+#[test]
+fn test_caption_text_kept() {
+    let opts = Options {
+        latex: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "\\begin{figure}\n\\caption[Short]{A \\emph{long} caption.}\\label{f}\n\\end{figure}\n\
+         \\caption*{Unnumbered.}\n\\captionof{table}{Outside a float.} Then text.\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(
+        text,
+        "A long caption.\nUnnumbered.\nOutside a float.\n Then text.\n"
+    );
+}
+
+#[test]
+fn test_keep_math_text_alphabet_letters() {
+    let opts = Options {
+        latex: true,
+        keep_math_text: true,
+        ..Default::default()
+    };
+    let input = "For $x \\in \\mathbb{R}$ and loss \\(\\mathcal{L}\\).\n";
+    let text = detex_str(input, opts.clone()).unwrap();
+    assert_eq!(text, "For R and loss L.\n");
+
+    let text = detex_str(input, opts.with_math_letter_name("\\mathbb{R}", "reals")).unwrap();
+    assert_eq!(text, "For reals and loss L.\n");
+}
+
+#[test]
+fn test_keep_math_text_spacing_separates_words() {
+    let opts = Options {
+        latex: true,
+        keep_math_text: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Sets $\\mathbb{R}\\quad\\mathbb{N}$ and \\[\\text{if}\\,\\text{then}\\qquad\\mathcal{L}\\]\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "Sets R N and if then L\n");
+
+    let opts = Options {
+        keep_math_identifiers: true,
+        ..opts
+    };
+    let text = detex_str("$a \\quad b$ and $a\\,b\\qquad c$\n", opts).unwrap();
+    assert_eq!(text, "a b and a b c\n");
+}
+
+#[test]
+fn test_keep_math_text_in_substack() {
+    let opts = Options {
+        latex: true,
+        keep_math_text: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "Sum $\\sum_{\\substack{\\text{all}\\\\\\mathbb{N}}} x_i$ here.\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "Sum all N here.\n");
+
+    let opts = Options {
+        keep_math_identifiers: true,
+        ..opts
+    };
+    let text = detex_str("Sum $\\sum_{\\substack{i=1\\\\j=2}}$ here.\n", opts.clone()).unwrap();
+    assert_eq!(text, "Sum i j here.\n");
+
+    let text = detex_str(
+        "\\begin{equation}\n\\sum_{\\substack{i<n\\\\j}} x_{ij} = \\text{total}\n\\end{equation}\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "i n j x ij total\n");
+}
+
+#[test]
+fn test_cite_placeholder() {
+    let opts = Options {
+        latex: true,
+        cite_placeholder: Some("[CITATION]".to_string()),
+        ..Default::default()
+    };
+    let text = detex_str(
+        "As shown in \\cite[p.~3]{knuth84}, it works.\n",
+        opts.clone(),
+    )
+    .unwrap();
+    assert_eq!(text, "As shown in [CITATION], it works.\n");
+
+    let text = detex_str(
+        "As shown in \\cite{knuth84}, it works.\n",
+        Options {
+            cite_placeholder: None,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "As shown in, it works.\n");
+}
+
+#[test]
+fn test_count_only() {
+    let opts = Options {
+        latex: true,
+        word: true,
+        count_only: true,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex
+        .process_reader(
+            std::io::Cursor::new(
+                b"The cat and the Dog, $x$ well-known.\nHello\\footnote{note here} world \\verb|x y|\n"
+                    .to_vec(),
+            ),
+            "count",
+        )
+        .unwrap();
+    assert_eq!(detex.word_count(), 11);
+    assert_eq!(detex.unique_word_count(), 10);
+    drop(detex);
+    assert!(output.is_empty());
+}
+
+#[test]
+fn test_source_map_spans() {
+    let dir = scratch_dir("source-map");
+    fs::write(dir.join("part.tex"), "Inner \\emph{words}\n").unwrap();
+    let main = dir.join("main.tex");
+    fs::write(&main, "Hi \\textbf{there}\n\\input{part}\n").unwrap();
+
+    let opts = Options {
+        input_paths: vec![dir.to_string_lossy().into_owned()],
+        track_source_map: true,
+        ..Default::default()
+    };
+    let main = main.to_string_lossy().into_owned();
+    let mut output = Vec::new();
+    let mut detex = Detex::new(opts, &mut output);
+    detex.process_file(&main).unwrap();
+    let spans = detex.source_map().to_vec();
+    drop(detex);
+
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "Hi there\nInner words\n\n"
+    );
+    let span = |start, len, file: &str, line, column| SourceSpan {
+        start,
+        len,
+        file: file.to_string(),
+        line,
+        column,
+    };
+    assert_eq!(
+        spans,
+        [
+            span(0, 3, &main, 1, 1),
+            span(3, 5, &main, 1, 12),
+            span(8, 1, &main, 1, 18),
+            span(9, 6, "part", 1, 1),
+            span(15, 5, "part", 1, 13),
+            span(20, 1, "part", 1, 19),
+            span(21, 1, &main, 2, 13),
+        ]
+    );
+}
+
+#[test]
+fn test_caption_drops_inline_label() {
+    let opts = Options {
+        latex: true,
+        cite: true,
+        ..Default::default()
+    };
+    let text = detex_str(
+        "\\caption{Foo\\label{fig:foo}}\n\\caption[Short]{Bar \\label{fig:bar} baz.}\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "Foo\nBar  baz.\n");
+}
+
+#[test]
+fn test_caption_drops_footnote() {
+    let text = detex_str(
+        "\\caption{Foo\\footnote{bar \\emph{x}} more.}\nText\\footnote{note}.\n",
+        Options::default().with_latex(true),
+    )
+    .unwrap();
+    assert_eq!(text, "Foo more.\nText(note).\n");
+}
+
+#[test]
+fn test_reference_ranges() {
+    let input = "See \\crefrange{eq:a--1}{eq:b} and \\vrefrange{first}{last}.\n";
+    let opts = Options {
+        latex: true,
+        cite: true,
+        ..Default::default()
+    };
+    let text = detex_str(input, opts.clone()).unwrap();
+    assert_eq!(text, "See eq:a--1,eq:b and first,last.\n");
+
+    let text = detex_str(
+        input,
+        Options {
+            word: true,
+            ..opts.clone()
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "See\neq\na\neq\nb\nand\nfirst\nlast\n");
+
+    let text = detex_str(
+        input,
+        Options {
+            cite: false,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "See  and .\n");
+}
+
+#[test]
+fn test_src_loc_with_column() {
+    let opts = Options {
+        src_loc: true,
+        src_loc_col: true,
+        ..Default::default()
+    };
+    let text = detex_str("Hello \\emph{x}\n  \\textbf{Indented} text\n", opts.clone()).unwrap();
+    assert_eq!(
+        text,
+        "<string>:1:1: Hello x\n<string>:2:1:   Indented text\n"
+    );
+
+    let text = detex_str(
+        "Hello\n",
+        Options {
+            src_loc_col: false,
+            ..opts
+        },
+    )
+    .unwrap();
+    assert_eq!(text, "<string>:1: Hello\n");
+}
+
+#[test]
+fn test_src_loc_after_display_math() {
+    let opts = Options {
+        latex: true,
+        src_loc: true,
+        ..Default::default()
+    };
+    let text = detex_str("A\n\\[\n  x = 1\n  + 2\n\\]\nafter\n", opts).unwrap();
+    assert_eq!(
+        text,
+        "<string>:1: A\n<string>:2: \n<string>:3: \n<string>:4: \n<string>:5: \n<string>:6: after\n"
+    );
+}
+
+#[test]
+fn test_crlf_line_endings() {
+    let opts = Options {
+        src_loc: true,
+        ..Default::default()
+    };
+    let text = detex_str("One \\emph{two}\r\nthree\rfour\r\n\r\nfive\n", opts).unwrap();
+    assert_eq!(
+        text,
+        "<string>:1: One two\n<string>:2: three\n<string>:3: four\n<string>:4: \n<string>:5: five\n"
+    );
+}
+
+#[test]
+fn test_crlf_split_across_reads() {
+    // One byte per read, so \r and \n arrive separately
+    let mut output = Vec::new();
+    let mut detex = Detex::new(Options::default(), &mut output);
+    detex
+        .process_reader(OneByteReader(b"a\r\nb\r\n"), "bytes")
+        .unwrap();
+    drop(detex);
+    assert_eq!(String::from_utf8(output).unwrap(), "a\nb\n");
+}
+
+#[test]
+fn test_env_aliases() {
+    let mut opts = Options {
+        latex: true,
+        ..Default::default()
+    }
+    .with_env_ignore("equation");
+    opts.env_aliases
+        .insert("myverb".to_string(), "verbatim".to_string());
+    opts.env_aliases
+        .insert("myeq".to_string(), "equation".to_string());
+    let text = detex_str(
+        "A\n\\begin{myverb}\n\\emph{kept} $x$\n\\end{myverb}\nB \\begin{myeq}x = y\\end{myeq} C\n",
+        opts,
+    )
+    .unwrap();
+    assert_eq!(text, "A\n\n\\emph{kept} $x$\n\nB  C\n");
+}
+
+#[test]
+fn test_tilde_output_modes() {
+    let input = "See Figure~2 and Mr.~Smith.\n";
+    for (tilde, expected) in [
+        (TildeOutput::Space, "See Figure 2 and Mr. Smith.\n"),
+        (TildeOutput::Nbsp, "See Figure\u{a0}2 and Mr.\u{a0}Smith.\n"),
+        (TildeOutput::Literal, "See Figure~2 and Mr.~Smith.\n"),
+    ] {
+        let opts = Options::default().with_tilde_output(tilde);
+        assert_eq!(detex_str(input, opts).unwrap(), expected, "{:?}", tilde);
+    }
+    assert_eq!(
+        detex_str(input, Options::default()).unwrap(),
+        "See Figure 2 and Mr. Smith.\n"
+    );
+}